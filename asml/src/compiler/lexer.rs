@@ -48,6 +48,10 @@ impl Lexer {
 
     fn devour_whitespace(&mut self) {
         while is_whitespace(self.cur_ch) {
+            // Blank lines are skipped here, they still count
+            if self.cur_ch == b'\n' {
+                self.reset_pos();
+            }
             self.read_char();
         }
     }
@@ -123,7 +127,8 @@ impl Iterator for Lexer {
         let tok = match self.cur_ch {
             b':' => {
                 self.read_char();
-                some_token!(tokent::LABEL, self.read_identifier())
+                // The identifier ends on the next character, don't skip it
+                return some_token!(tokent::LABEL, self.read_identifier());
             }
             b'#' => some_token!(tokent::IMMEDIATE),
             b',' => some_token!(tokent::COMMA),
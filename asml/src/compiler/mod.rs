@@ -8,18 +8,128 @@ use std::io::{BufReader, Read};
 use std::path::Path;
 
 use asml_vm::Code;
+use asml_vm::symbols::SymbolTable;
 use parser::{Parser, ParserError};
 
-pub fn compile_file(filepath: &Path) -> Result<Code, ParserError> {
-    let file = File::open(filepath).unwrap();
+/// The result of compiling a source file.
+pub struct Compiled {
+    pub code: Code,
+    /// Addresses of the labels
+    pub symbols: SymbolTable,
+    /// Source line and address of each instruction, sorted by address
+    pub lines: Vec<(u32, u16)>,
+}
+
+pub fn compile_file(filepath: &Path) -> Result<Compiled, ParserError> {
+    let file = File::open(filepath)
+        .map_err(|e| ParserError::Io(format!("Unable to open {}: {}", filepath.display(), e)))?;
     let buf = BufReader::new(file);
-    let reader = buf.bytes();
-    let lex = lexer::Lexer::new(reader);
+    compile(lexer::Lexer::new(buf.bytes()))
+}
+
+#[cfg(test)]
+pub fn compile_str(src: &str) -> Result<Code, ParserError> {
+    let bytes = src.as_bytes().to_vec();
+    compile(lexer::Lexer::new(bytes.into_iter().map(Ok))).map(|c| c.code)
+}
+
+fn compile(lex: lexer::Lexer) -> Result<Compiled, ParserError> {
     let mut prog = Parser::new(lex).parse()?;
 
     if let Err(s) = linker::link(&mut prog) {
         Err(ParserError::InvalidCode(s))
     } else {
-        Ok(prog.to_code())
+        let mut lines = prog.lines.clone();
+        lines.sort_by_key(|(_, addr)| *addr);
+
+        Ok(Compiled {
+            code: prog.to_code(),
+            symbols: prog.to_symbols(),
+            lines,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use asml_vm::disasm::disassemble_code;
+
+    // Flatten sections into (address, byte) pairs so differently split
+    // sections compare equal. The bank is the high byte of the address.
+    fn image(code: &Code) -> Vec<(usize, u8)> {
+        let mut bytes: Vec<(usize, u8)> = code
+            .iter()
+            .flat_map(|s| {
+                let start = usize::from(s.bank) << 16 | usize::from(s.org);
+                (start..).zip(s.code.iter().cloned())
+            })
+            .collect();
+        bytes.sort();
+        bytes
+    }
+
+    #[test]
+    fn test_missing_file() {
+        let err = compile_file(Path::new("missing.asml")).err().unwrap();
+        assert!(err.to_string().starts_with("Unable to open missing.asml: "));
+    }
+
+    #[test]
+    fn test_disassembly_round_trip() {
+        let sources = [
+            include_str!("../../../examples/Echo.asml"),
+            include_str!("../../../examples/Hello.asml"),
+            include_str!("../../../examples/PrintOrgedString.asml"),
+            include_str!("../../../examples/PrintX.asml"),
+            include_str!("../../../examples/Timer.asml"),
+            include_str!("../../../examples/subroutines.asml"),
+            "ROTR %A #4\nROTL %2 #0x02\nFCB 0xFF, 0x1F, 0x0E\nLDSP #0x100\nLDSP %A\n",
+            "HALT\nBANK 2\nFCB 0x02\nBANK 1\nFCB 0x01\nORG 0x9000\nRTN\n",
+        ];
+
+        for src in sources.iter() {
+            let code = compile_str(src).unwrap();
+            let disasm = disassemble_code(&code);
+            let recompiled = compile_str(&disasm).unwrap();
+
+            assert_eq!(image(&code), image(&recompiled), "{}", disasm);
+        }
+    }
+
+    #[test]
+    fn test_banks() {
+        let code = compile_str(
+            "ORG 0x8000\nFCB 0x00\nBANK 1\n:one\nFCB 0x01\nORG 0xBFFF\nFCB 0x02\nBANK 0\nORG 0x10\nFCB 0x03\n",
+        )
+        .unwrap();
+        let sections: Vec<(u8, u16, &[u8])> = code
+            .iter()
+            .filter(|s| !s.code.is_empty())
+            .map(|s| (s.bank, s.org, &s.code[..]))
+            .collect();
+        assert_eq!(
+            sections,
+            vec![
+                (0, 0x10, &[0x03][..]),
+                (0, 0x8000, &[0x00]),
+                (1, 0x8000, &[0x01]),
+                (1, 0xBFFF, &[0x02]),
+            ]
+        );
+
+        assert!(compile_str("BANK 1\nORG 0xBFFF\nFDB 0x0102\n").is_err());
+        assert!(compile_str("BANK 1\nORG 0x1000\nHALT\n").is_err());
+        assert!(compile_str("BANK 256\n").is_err());
+    }
+
+    #[test]
+    fn test_line_numbers() {
+        let src = "LDSP #0xFF\n\n:main  \n    LOAD %1 #1 ; one\n\nHALT\n";
+        let bytes = src.as_bytes().to_vec();
+        let compiled = compile(lexer::Lexer::new(bytes.into_iter().map(Ok))).unwrap();
+
+        assert_eq!(compiled.lines, vec![(1, 0), (4, 3), (6, 7)]);
     }
 }
@@ -59,7 +59,6 @@ impl<L: Iterator<Item = Token>> Parser<L> {
         self.read_token();
         let reg1 = self.parse_register()?;
 
-        self.read_token();
         self.expect_token(TokenType::IMMEDIATE)?;
 
         // Arg 2
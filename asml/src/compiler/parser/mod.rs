@@ -13,6 +13,7 @@ pub enum ParserError {
     InvalidCode(String),
     ExpectedToken(String),
     ValidationError(String),
+    Io(String),
 }
 
 impl fmt::Display for ParserError {
@@ -21,6 +22,7 @@ impl fmt::Display for ParserError {
             ParserError::InvalidCode(s) => write!(f, "{}", s),
             ParserError::ExpectedToken(s) => write!(f, "{}", s),
             ParserError::ValidationError(s) => write!(f, "{}", s),
+            ParserError::Io(s) => write!(f, "{}", s),
         }
     }
 }
@@ -31,6 +33,7 @@ impl fmt::Debug for ParserError {
             ParserError::InvalidCode(s) => write!(f, "{}", s),
             ParserError::ExpectedToken(s) => write!(f, "{}", s),
             ParserError::ValidationError(s) => write!(f, "{}", s),
+            ParserError::Io(s) => write!(f, "{}", s),
         }
     }
 }
@@ -44,8 +47,8 @@ pub struct Parser<L: Iterator<Item = Token>> {
 
 impl<L: Iterator<Item = Token>> Parser<L> {
     pub fn new(mut lexer: L) -> Self {
-        let cur = lexer.next().unwrap();
-        let peek = lexer.next().unwrap();
+        let cur = next_token(&mut lexer);
+        let peek = next_token(&mut lexer);
 
         Parser {
             lexer,
@@ -57,6 +60,20 @@ impl<L: Iterator<Item = Token>> Parser<L> {
 
     pub fn parse(mut self) -> Result<Program, ParserError> {
         while self.cur_tok.name != TokenType::EOF {
+            if !matches!(
+                self.cur_tok.name,
+                TokenType::END_INST
+                    | TokenType::COMMENT
+                    | TokenType::LABEL
+                    | TokenType::RMB
+                    | TokenType::ORG
+                    | TokenType::BANK
+                    | TokenType::FCB
+                    | TokenType::FDB
+            ) {
+                self.prog.add_line(self.cur_tok.line);
+            }
+
             let res: Result<(), ParserError> = match self.cur_tok.name {
                 // Skip empty lines
                 TokenType::END_INST | TokenType::COMMENT => {
@@ -72,6 +89,14 @@ impl<L: Iterator<Item = Token>> Parser<L> {
                 TokenType::XFER => self.parse_reg_reg(OpCode::XFER),
 
                 TokenType::ADD => self.parse_inst(OpCode::ADDI, OpCode::ADDA, OpCode::ADDR),
+                TokenType::SUB => self.parse_inst(OpCode::SUBI, OpCode::SUBA, OpCode::SUBR),
+                TokenType::CMP => self.parse_inst(OpCode::CMPI, OpCode::CMPA, OpCode::CMPR),
+                TokenType::MUL => self.parse_inst(OpCode::MULI, OpCode::MULA, OpCode::MULR),
+                TokenType::DIV => self.parse_inst(OpCode::DIVI, OpCode::DIVA, OpCode::DIVR),
+                TokenType::MOD => self.parse_inst(OpCode::MODI, OpCode::MODA, OpCode::MODR),
+                TokenType::SHL => self.parse_inst(OpCode::SHLI, OpCode::SHLA, OpCode::SHLR),
+                TokenType::SHR => self.parse_inst(OpCode::SHRI, OpCode::SHRA, OpCode::SHRR),
+                TokenType::ASR => self.parse_inst(OpCode::ASRI, OpCode::ASRA, OpCode::ASRR),
 
                 TokenType::OR => self.parse_inst(OpCode::ORI, OpCode::ORA, OpCode::ORR),
                 TokenType::AND => self.parse_inst(OpCode::ANDI, OpCode::ANDA, OpCode::ANDR),
@@ -87,6 +112,12 @@ impl<L: Iterator<Item = Token>> Parser<L> {
 
                 TokenType::JMP => self.parse_reg_num(OpCode::JMP),
                 TokenType::JMPA => self.parse_num(OpCode::JMPA),
+                TokenType::JZ => self.parse_num(OpCode::JZ),
+                TokenType::JNZ => self.parse_num(OpCode::JNZ),
+                TokenType::JC => self.parse_num(OpCode::JC),
+                TokenType::JNC => self.parse_num(OpCode::JNC),
+                TokenType::JN => self.parse_num(OpCode::JN),
+                TokenType::JV => self.parse_num(OpCode::JV),
 
                 TokenType::LDSP => {
                     self.parse_inst_no_dest(OpCode::LDSPI, OpCode::LDSPA, OpCode::LDSPR)
@@ -95,6 +126,9 @@ impl<L: Iterator<Item = Token>> Parser<L> {
                 TokenType::HALT => self.parse_no_args(OpCode::HALT),
                 TokenType::NOOP => self.parse_no_args(OpCode::NOOP),
                 TokenType::RTN => self.parse_no_args(OpCode::RTN),
+                TokenType::SEI => self.parse_no_args(OpCode::SEI),
+                TokenType::CLI => self.parse_no_args(OpCode::CLI),
+                TokenType::RTI => self.parse_no_args(OpCode::RTI),
 
                 TokenType::DEBUG => self.parse_no_args(OpCode::DEBUG),
 
@@ -102,6 +136,7 @@ impl<L: Iterator<Item = Token>> Parser<L> {
                 TokenType::LABEL => self.make_label(),
                 TokenType::RMB => self.ins_rmb(),
                 TokenType::ORG => self.ins_org(),
+                TokenType::BANK => self.ins_bank(),
                 TokenType::FCB => self.raw_data_fcb(),
                 TokenType::FDB => self.raw_data_fdb(),
                 _ => Err(ParserError::InvalidCode(format!(
@@ -123,7 +158,7 @@ impl<L: Iterator<Item = Token>> Parser<L> {
 
     fn read_token(&mut self) {
         self.cur_tok = self.peek_tok.clone();
-        self.peek_tok = self.lexer.next().unwrap();
+        self.peek_tok = next_token(&mut self.lexer);
     }
 
     // Utility methods
@@ -252,6 +287,21 @@ impl<L: Iterator<Item = Token>> Parser<L> {
         }
     }
 
+    fn ins_bank(&mut self) -> Result<(), ParserError> {
+        self.read_token();
+        if !self.cur_token_is(TokenType::NUMBER) {
+            return Err(self.token_err(TokenType::NUMBER));
+        }
+
+        match parse_u16(&self.cur_tok.literal).and_then(|n| u8::try_from(n).ok()) {
+            Some(bank) => {
+                self.prog.add_bank_part(bank);
+                Ok(())
+            }
+            None => Err(self.parse_err("invalid bank number")),
+        }
+    }
+
     // Argument parser methods
     fn parse_address(&mut self, pcoffset: u16) -> Result<u16, ParserError> {
         match self.cur_tok.name {
@@ -323,22 +373,24 @@ impl<L: Iterator<Item = Token>> Parser<L> {
     }
 }
 
+// A comment runs to the end of the line, including the newline, so it's
+// treated as the end of the instruction it follows
+fn next_token<L: Iterator<Item = Token>>(lexer: &mut L) -> Token {
+    let tok = lexer.next().unwrap();
+    if tok.name == TokenType::COMMENT {
+        Token::simple(TokenType::END_INST, tok.line, tok.col)
+    } else {
+        tok
+    }
+}
+
 fn parse_u16(s: &str) -> Option<u16> {
     if s.starts_with('!') {
-        match u16::from_str_radix(s.trim_start_matches('!'), 16) {
-            Ok(n) => Some(n),
-            Err(_) => None,
-        }
+        u16::from_str_radix(s.trim_start_matches('!'), 16).ok()
     } else if s.starts_with("0x") {
-        match u16::from_str_radix(s.trim_start_matches("0x"), 16) {
-            Ok(n) => Some(n),
-            Err(_) => None,
-        }
+        u16::from_str_radix(s.trim_start_matches("0x"), 16).ok()
     } else {
-        match s.parse::<u16>() {
-            Ok(n) => Some(n),
-            Err(_) => None,
-        }
+        s.parse::<u16>().ok()
     }
 }
 
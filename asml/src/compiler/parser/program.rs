@@ -1,5 +1,7 @@
 use std::collections::HashMap;
 
+use asml_vm::devices::BANK_WINDOW;
+use asml_vm::symbols::SymbolTable;
 use asml_vm::{Code, CodeSection};
 
 #[derive(Debug)]
@@ -15,16 +17,18 @@ pub type LabelMap = HashMap<String, u16>;
 pub struct CodePart {
     pub bytes: Vec<u8>,
     pub start_pc: u16,
+    pub bank: u8,
     pc: u16,
     pub link_map: LabelLinkMap,
 }
 
 impl CodePart {
-    pub fn new(pc: u16) -> Self {
+    pub fn new(pc: u16, bank: u8) -> Self {
         CodePart {
             bytes: Vec::with_capacity(100),
             link_map: HashMap::new(),
             start_pc: pc,
+            bank,
             pc,
         }
     }
@@ -35,14 +39,17 @@ pub struct Program {
     pub parts: Vec<CodePart>,
     part_i: usize,
     pub labels: LabelMap,
+    /// Source line and address of each instruction
+    pub lines: Vec<(u32, u16)>,
 }
 
 impl Program {
     pub fn new() -> Self {
         Program {
-            parts: vec![CodePart::new(0)],
+            parts: vec![CodePart::new(0, 0)],
             part_i: 0,
             labels: HashMap::new(),
+            lines: Vec::new(),
         }
     }
 
@@ -60,6 +67,11 @@ impl Program {
             .insert(name.to_owned(), self.parts[self.part_i].pc);
     }
 
+    pub fn add_line(&mut self, line: u32) {
+        let pc = self.pc();
+        self.lines.push((line, pc));
+    }
+
     pub fn add_link(&mut self, pc_offset: u16, name: &str, offset: i16) {
         let pc = self.pc() - self.parts[self.part_i].start_pc;
         self.parts[self.part_i].link_map.insert(
@@ -71,8 +83,16 @@ impl Program {
         );
     }
 
+    /// Start a new part at `pc` in the current bank.
     pub fn add_code_part(&mut self, pc: u16) {
-        self.parts.push(CodePart::new(pc));
+        let bank = self.parts[self.part_i].bank;
+        self.parts.push(CodePart::new(pc, bank));
+        self.part_i += 1;
+    }
+
+    /// Start a new part at the beginning of the bank window in `bank`.
+    pub fn add_bank_part(&mut self, bank: u8) {
+        self.parts.push(CodePart::new(*BANK_WINDOW.start(), bank));
         self.part_i += 1;
     }
 
@@ -82,6 +102,7 @@ impl Program {
         for part in &self.parts {
             let cs = CodeSection {
                 org: part.start_pc,
+                bank: part.bank,
                 code: part.bytes.clone(),
             };
 
@@ -91,22 +112,54 @@ impl Program {
         code
     }
 
+    pub fn to_symbols(&self) -> SymbolTable {
+        let mut symbols = SymbolTable::new();
+        for (name, addr) in &self.labels {
+            symbols.insert(name, *addr);
+        }
+        symbols
+    }
+
     pub fn validate(&mut self) -> Result<(), String> {
-        self.parts.sort_by_key(|p| p.start_pc);
+        self.parts.sort_by_key(|p| (p.bank, p.start_pc));
+
+        for code in self
+            .parts
+            .iter()
+            .filter(|p| p.bank > 0 && !p.bytes.is_empty())
+        {
+            let end = usize::from(code.start_pc) + code.bytes.len() - 1;
+            if !BANK_WINDOW.contains(&code.start_pc) || end > usize::from(*BANK_WINDOW.end()) {
+                return Err(format!(
+                    "bank {} code at 0x{:04X}-0x{:04X} is outside the bank window 0x{:04X}-0x{:04X}",
+                    code.bank,
+                    code.start_pc,
+                    end,
+                    BANK_WINDOW.start(),
+                    BANK_WINDOW.end()
+                ));
+            }
+        }
+
+        // Empty parts, such as the one started by BANK, can't overlap
+        let parts: Vec<&CodePart> = self.parts.iter().filter(|p| !p.bytes.is_empty()).collect();
+
+        for pair in parts.windows(2) {
+            let (code, next) = (pair[0], pair[1]);
 
-        for (i, code) in self.parts.iter().enumerate() {
-            if i == self.parts.len() - 1 {
-                break;
+            // Parts in different banks can share addresses in the bank window
+            if code.bank != next.bank {
+                continue;
             }
 
-            if code.start_pc + (code.bytes.len() as u16) > self.parts[i + 1].start_pc {
+            if code.start_pc + (code.bytes.len() as u16) > next.start_pc {
                 return Err(format!(
                     "overlapping address regions:
 Origin 0x{:04X} goes to 0x{:04X}
 Origin 0x{:04X} begins inside region",
                     code.start_pc,
                     code.start_pc + (code.bytes.len() as u16),
-                    self.parts[i + 1].start_pc
+                    next.start_pc
                 ));
             }
         }
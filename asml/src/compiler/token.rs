@@ -20,6 +20,14 @@ pub enum TokenType {
     STR,
     XFER,
     ADD,
+    SUB,
+    CMP,
+    MUL,
+    DIV,
+    MOD,
+    SHL,
+    SHR,
+    ASR,
     OR,
     AND,
     XOR,
@@ -28,13 +36,23 @@ pub enum TokenType {
     JMP,
     HALT,
     JMPA,
+    JZ,
+    JNZ,
+    JC,
+    JNC,
+    JN,
+    JV,
     LDSP,
     PUSH,
     POP,
     CALL,
     RTN,
+    SEI,
+    CLI,
+    RTI,
     RMB,
     ORG,
+    BANK,
     FCB,
     FDB,
     DEBUG,
@@ -48,6 +66,14 @@ impl TokenType {
             "STR" => TokenType::STR,
             "XFER" => TokenType::XFER,
             "ADD" => TokenType::ADD,
+            "SUB" => TokenType::SUB,
+            "CMP" => TokenType::CMP,
+            "MUL" => TokenType::MUL,
+            "DIV" => TokenType::DIV,
+            "MOD" => TokenType::MOD,
+            "SHL" => TokenType::SHL,
+            "SHR" => TokenType::SHR,
+            "ASR" => TokenType::ASR,
             "OR" => TokenType::OR,
             "AND" => TokenType::AND,
             "XOR" => TokenType::XOR,
@@ -56,13 +82,23 @@ impl TokenType {
             "JMP" => TokenType::JMP,
             "HALT" => TokenType::HALT,
             "JMPA" => TokenType::JMPA,
+            "JZ" => TokenType::JZ,
+            "JNZ" => TokenType::JNZ,
+            "JC" => TokenType::JC,
+            "JNC" => TokenType::JNC,
+            "JN" => TokenType::JN,
+            "JV" => TokenType::JV,
             "LDSP" => TokenType::LDSP,
             "PUSH" => TokenType::PUSH,
             "POP" => TokenType::POP,
             "CALL" => TokenType::CALL,
             "RTN" => TokenType::RTN,
+            "SEI" => TokenType::SEI,
+            "CLI" => TokenType::CLI,
+            "RTI" => TokenType::RTI,
             "RMB" => TokenType::RMB,
             "ORG" => TokenType::ORG,
+            "BANK" => TokenType::BANK,
             "FCB" => TokenType::FCB,
             "FDB" => TokenType::FDB,
             "DEBUG" => TokenType::DEBUG,
@@ -93,6 +129,14 @@ impl fmt::Display for TokenType {
                 TokenType::STR => "STR",
                 TokenType::XFER => "XFER",
                 TokenType::ADD => "ADD",
+                TokenType::SUB => "SUB",
+                TokenType::CMP => "CMP",
+                TokenType::MUL => "MUL",
+                TokenType::DIV => "DIV",
+                TokenType::MOD => "MOD",
+                TokenType::SHL => "SHL",
+                TokenType::SHR => "SHR",
+                TokenType::ASR => "ASR",
                 TokenType::OR => "OR",
                 TokenType::AND => "AND",
                 TokenType::XOR => "XOR",
@@ -101,13 +145,23 @@ impl fmt::Display for TokenType {
                 TokenType::JMP => "JMP",
                 TokenType::HALT => "HALT",
                 TokenType::JMPA => "JMPA",
+                TokenType::JZ => "JZ",
+                TokenType::JNZ => "JNZ",
+                TokenType::JC => "JC",
+                TokenType::JNC => "JNC",
+                TokenType::JN => "JN",
+                TokenType::JV => "JV",
                 TokenType::LDSP => "LDSP",
                 TokenType::PUSH => "PUSH",
                 TokenType::POP => "POP",
                 TokenType::CALL => "CALL",
                 TokenType::RTN => "RTN",
+                TokenType::SEI => "SEI",
+                TokenType::CLI => "CLI",
+                TokenType::RTI => "RTI",
                 TokenType::RMB => "RMB",
                 TokenType::ORG => "ORG",
+                TokenType::BANK => "BANK",
                 TokenType::FCB => "FCB",
                 TokenType::FDB => "FDB",
                 TokenType::DEBUG => "DEBUG",
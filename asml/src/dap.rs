@@ -0,0 +1,715 @@
+//! Debug Adapter Protocol server.
+//!
+//! Lets editors debug an ASML source file. Messages are read from stdin and
+//! written to stdout, each with a `Content-Length` header. The program is
+//! compiled on `launch`, line breakpoints are mapped to the address of the
+//! first instruction on or after the line, and printer output is sent to the
+//! debug console.
+
+use std::cell::RefCell;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+
+use asml_vm::devices::Keyboard;
+use asml_vm::opcodes::OpCode;
+use asml_vm::{
+    FLAG_CARRY, FLAG_INTERRUPT, FLAG_NEGATIVE, FLAG_OVERFLOW, FLAG_ZERO, StepOutcome, VM, VmError,
+};
+use serde_json::{Value, json};
+
+use crate::compiler::{self, Compiled};
+
+const THREAD_ID: i64 = 1;
+
+// Variable references of the scopes. Each 256 byte page of memory is
+// MEMORY_PAGE_REF plus the page number.
+const REGISTERS_REF: i64 = 1;
+const FLAGS_REF: i64 = 2;
+const MEMORY_REF: i64 = 3;
+const MEMORY_PAGE_REF: i64 = 0x100;
+
+// Instructions executed between checks for new requests while running
+const POLL_STEPS: usize = 1024;
+
+/// Serve a single debug session over stdin and stdout.
+pub fn serve_stdio() {
+    let (tx, rx) = mpsc::channel();
+
+    // Requests such as pause have to be read while the program is running
+    thread::spawn(move || {
+        let mut input = BufReader::new(io::stdin());
+        while let Ok(Some(msg)) = read_message(&mut input) {
+            if tx.send(msg).is_err() {
+                break;
+            }
+        }
+    });
+
+    Adapter::new(rx, Box::new(io::stdout())).run();
+}
+
+fn read_message(input: &mut dyn BufRead) -> io::Result<Option<Value>> {
+    let mut len = None;
+
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+
+        let line = line.trim_end();
+        if line.is_empty() && len.is_some() {
+            break;
+        }
+        if let Some(n) = line.strip_prefix("Content-Length:") {
+            len = n.trim().parse::<usize>().ok();
+        }
+    }
+
+    let mut body = vec![0; len.unwrap_or_default()];
+    input.read_exact(&mut body)?;
+    serde_json::from_slice(&body)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+// Numbers and sends messages to the client. Printer output is held until a
+// full line has been printed or the program stops.
+struct Client {
+    out: Box<dyn Write>,
+    seq: i64,
+    output: Vec<u8>,
+}
+
+impl Client {
+    fn send(&mut self, mut msg: Value) {
+        self.seq += 1;
+        msg["seq"] = json!(self.seq);
+
+        let body = msg.to_string();
+        // There's no one left to report errors to if the client is gone
+        let _ = write!(self.out, "Content-Length: {}\r\n\r\n{}", body.len(), body)
+            .and_then(|_| self.out.flush());
+    }
+
+    fn event(&mut self, event: &str, body: Value) {
+        self.send(json!({"type": "event", "event": event, "body": body}));
+    }
+
+    fn print(&mut self, data: &[u8]) {
+        self.output.extend_from_slice(data);
+        if data.contains(&b'\n') {
+            self.flush_output();
+        }
+    }
+
+    fn flush_output(&mut self) {
+        if !self.output.is_empty() {
+            let text = String::from_utf8_lossy(&self.output).into_owned();
+            self.output.clear();
+            self.event("output", json!({"category": "stdout", "output": text}));
+        }
+    }
+}
+
+// Printer sink writing to the debug console
+struct ConsoleOutput(Rc<RefCell<Client>>);
+
+impl Write for ConsoleOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().print(buf);
+        Ok(buf.len())
+    }
+
+    // The printer flushes after every character, output is sent by line
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// How far to run before stopping on its own
+#[derive(Debug, Clone, Copy)]
+enum RunMode {
+    Continue,
+    // Step over a subroutine call, stopping at the return address
+    Over { addr: u16, sp: u16 },
+    // Stop after returning from the current subroutine
+    Out { sp: u16 },
+}
+
+struct Session {
+    vm: VM,
+    compiled: Compiled,
+    path: PathBuf,
+    stop_on_entry: bool,
+    // Addresses of the line breakpoints
+    breakpoints: Vec<u16>,
+    crashed: bool,
+}
+
+pub struct Adapter {
+    client: Rc<RefCell<Client>>,
+    requests: Receiver<Value>,
+    session: Option<Session>,
+    running: Option<RunMode>,
+    // Events to send after the response to the current request
+    events: Vec<(&'static str, Value)>,
+    done: bool,
+}
+
+impl Adapter {
+    pub fn new(requests: Receiver<Value>, out: Box<dyn Write>) -> Self {
+        Adapter {
+            client: Rc::new(RefCell::new(Client {
+                out,
+                seq: 0,
+                output: Vec::new(),
+            })),
+            requests,
+            session: None,
+            running: None,
+            events: Vec::new(),
+            done: false,
+        }
+    }
+
+    /// Handle requests until the client disconnects.
+    pub fn run(&mut self) {
+        while !self.done {
+            if let Some(mode) = self.running {
+                self.run_slice(mode);
+                self.send_events();
+
+                match self.requests.try_recv() {
+                    Ok(msg) => self.handle(msg),
+                    Err(TryRecvError::Empty) => {}
+                    Err(TryRecvError::Disconnected) => return,
+                }
+            } else {
+                match self.requests.recv() {
+                    Ok(msg) => self.handle(msg),
+                    Err(_) => return,
+                }
+            }
+        }
+    }
+
+    fn handle(&mut self, msg: Value) {
+        if msg["type"] != "request" {
+            return;
+        }
+        let command = msg["command"].as_str().unwrap_or_default();
+        let args = &msg["arguments"];
+
+        let res = match command {
+            "initialize" => Ok(json!({
+                "supportsConfigurationDoneRequest": true,
+                "supportsTerminateRequest": true,
+            })),
+            "launch" => self.launch(args),
+            "setBreakpoints" => self.set_breakpoints(args),
+            "setExceptionBreakpoints" => Ok(json!({})),
+            "configurationDone" => self.configuration_done(),
+            "threads" => Ok(json!({"threads": [{"id": THREAD_ID, "name": "main"}]})),
+            "stackTrace" => self.stack_trace(),
+            "scopes" => Ok(json!({"scopes": [
+                {"name": "Registers", "variablesReference": REGISTERS_REF, "expensive": false},
+                {"name": "Flags", "variablesReference": FLAGS_REF, "expensive": false},
+                {"name": "Memory", "variablesReference": MEMORY_REF, "expensive": true},
+            ]})),
+            "variables" => self.variables(args),
+            "continue" => self.resume(RunMode::Continue),
+            "next" => self.next(),
+            "stepIn" => self.step_in(),
+            "stepOut" => match &self.session {
+                Some(s) => self.resume(RunMode::Out { sp: s.vm.sp() }),
+                None => Err(not_launched()),
+            },
+            "pause" => {
+                if self.running.is_some() {
+                    self.stop("pause", None);
+                }
+                Ok(json!({}))
+            }
+            "disconnect" => {
+                self.done = true;
+                Ok(json!({}))
+            }
+            "terminate" => {
+                self.running = None;
+                self.events.push(("terminated", json!({})));
+                Ok(json!({}))
+            }
+            _ => Err(format!("Unsupported request {}", command)),
+        };
+
+        let mut response = json!({
+            "type": "response",
+            "request_seq": msg["seq"],
+            "command": command,
+            "success": res.is_ok(),
+        });
+        match res {
+            Ok(body) => response["body"] = body,
+            Err(e) => response["message"] = json!(e),
+        }
+
+        self.client.borrow_mut().send(response);
+        self.send_events();
+    }
+
+    fn send_events(&mut self) {
+        let mut client = self.client.borrow_mut();
+        for (event, body) in self.events.drain(..) {
+            client.event(event, body);
+        }
+    }
+
+    fn launch(&mut self, args: &Value) -> Result<Value, String> {
+        let path = args["program"]
+            .as_str()
+            .ok_or("No program given to launch")?;
+        let compiled = compiler::compile_file(Path::new(path)).map_err(|e| e.to_string())?;
+
+        let keyboard = match args["input"].as_str() {
+            Some(input) => Keyboard::from_bytes(
+                fs::read(input)
+                    .map_err(|e| format!("Unable to open input file {}: {}", input, e))?,
+            ),
+            // Stdin is used to talk to the client
+            None => Keyboard::from_bytes(Vec::new()),
+        };
+
+        let mut vm = VM::new();
+        vm.install_code(&compiled.code);
+        vm.attach_device(Box::new(keyboard));
+        vm.set_output(Box::new(ConsoleOutput(self.client.clone())));
+
+        self.session = Some(Session {
+            vm,
+            compiled,
+            path: PathBuf::from(path),
+            stop_on_entry: args["stopOnEntry"].as_bool().unwrap_or(false),
+            breakpoints: Vec::new(),
+            crashed: false,
+        });
+
+        // Breakpoints can be set once the program is loaded
+        self.events.push(("initialized", json!({})));
+        Ok(json!({}))
+    }
+
+    fn set_breakpoints(&mut self, args: &Value) -> Result<Value, String> {
+        let session = self.session.as_mut().ok_or_else(not_launched)?;
+
+        for addr in session.breakpoints.drain(..) {
+            session.vm.remove_breakpoint(addr);
+        }
+
+        let same_file = args["source"]["path"]
+            .as_str()
+            .is_some_and(|p| same_path(Path::new(p), &session.path));
+
+        let mut verified = Vec::new();
+        for bp in args["breakpoints"].as_array().into_iter().flatten() {
+            let line = bp["line"].as_u64().unwrap_or_default();
+
+            // The first instruction on or after the line
+            let location = session
+                .compiled
+                .lines
+                .iter()
+                .filter(|(l, _)| u64::from(*l) >= line)
+                .min_by_key(|(l, addr)| (*l, *addr))
+                .filter(|_| same_file);
+
+            match location {
+                Some((line, addr)) => {
+                    session.vm.add_breakpoint(*addr, None);
+                    session.breakpoints.push(*addr);
+                    verified.push(json!({"verified": true, "line": line}));
+                }
+                None => verified.push(json!({
+                    "verified": false,
+                    "line": line,
+                    "message": "No instruction on or after this line",
+                })),
+            }
+        }
+
+        Ok(json!({"breakpoints": verified}))
+    }
+
+    fn configuration_done(&mut self) -> Result<Value, String> {
+        let session = self.session.as_ref().ok_or_else(not_launched)?;
+
+        if session.stop_on_entry {
+            self.events.push((
+                "stopped",
+                json!({"reason": "entry", "threadId": THREAD_ID, "allThreadsStopped": true}),
+            ));
+        } else {
+            self.running = Some(RunMode::Continue);
+        }
+        Ok(json!({}))
+    }
+
+    fn stack_trace(&self) -> Result<Value, String> {
+        let session = self.session.as_ref().ok_or_else(not_launched)?;
+        let pc = session.vm.pc();
+
+        let name = session
+            .compiled
+            .symbols
+            .resolve(pc)
+            .unwrap_or_else(|| format!("0x{:04X}", pc));
+        let mut frame = json!({
+            "id": 1,
+            "name": name,
+            "line": 0,
+            "column": 0,
+            "instructionPointerReference": format!("0x{:04X}", pc),
+        });
+
+        // The line of the instruction at PC, or the closest one before it
+        if let Some((line, _)) = session
+            .compiled
+            .lines
+            .iter()
+            .rev()
+            .find(|(_, addr)| *addr <= pc)
+        {
+            frame["line"] = json!(line);
+            frame["column"] = json!(1);
+            frame["source"] = json!({
+                "name": session.path.file_name().map(|n| n.to_string_lossy()),
+                "path": session.path,
+            });
+        }
+
+        Ok(json!({"stackFrames": [frame], "totalFrames": 1}))
+    }
+
+    fn variables(&self, args: &Value) -> Result<Value, String> {
+        let vm = &self.session.as_ref().ok_or_else(not_launched)?.vm;
+        let var = |name: String, value: String| json!({"name": name, "value": value, "variablesReference": 0});
+
+        let variables: Vec<Value> = match args["variablesReference"].as_i64().unwrap_or_default() {
+            REGISTERS_REF => {
+                let mut vars: Vec<Value> = vm
+                    .registers()
+                    .iter()
+                    .enumerate()
+                    .map(|(i, r)| var(format!("%{}", i), format!("0x{:02X}", r)))
+                    .collect();
+                for r in 0xA..=0xD {
+                    let value = vm.register(r).unwrap_or_default();
+                    vars.push(var(format!("%{:X}", r), format!("0x{:04X}", value)));
+                }
+                vars.push(var("PC".to_owned(), format!("0x{:04X}", vm.pc())));
+                vars.push(var("SP".to_owned(), format!("0x{:04X}", vm.sp())));
+                vars
+            }
+            FLAGS_REF => [
+                ("Z", FLAG_ZERO),
+                ("C", FLAG_CARRY),
+                ("N", FLAG_NEGATIVE),
+                ("V", FLAG_OVERFLOW),
+                ("I", FLAG_INTERRUPT),
+            ]
+            .iter()
+            .map(|(name, f)| var((*name).to_owned(), ((vm.flags() & f > 0) as u8).to_string()))
+            .collect(),
+            MEMORY_REF => (0..256)
+                .map(|page| {
+                    json!({
+                        "name": format!("0x{:02X}00", page),
+                        "value": "",
+                        "variablesReference": MEMORY_PAGE_REF + page,
+                    })
+                })
+                .collect(),
+            r if (MEMORY_PAGE_REF..MEMORY_PAGE_REF + 256).contains(&r) => {
+                let page = ((r - MEMORY_PAGE_REF) as u16) << 8;
+                (0..16)
+                    .map(|row| {
+                        let addr = page + row * 16;
+                        let bytes: Vec<String> = (0..16)
+                            .map(|i| format!("{:02X}", vm.peek(addr.wrapping_add(i))))
+                            .collect();
+                        var(format!("0x{:04X}", addr), bytes.join(" "))
+                    })
+                    .collect()
+            }
+            _ => Vec::new(),
+        };
+
+        Ok(json!({"variables": variables}))
+    }
+
+    fn resume(&mut self, mode: RunMode) -> Result<Value, String> {
+        let session = self.session.as_ref().ok_or_else(not_launched)?;
+
+        if session.crashed {
+            self.finish(1);
+        } else {
+            self.running = Some(mode);
+        }
+        Ok(json!({"allThreadsContinued": true}))
+    }
+
+    // Subroutine calls are stepped over, anything else is a single step
+    fn next(&mut self) -> Result<Value, String> {
+        let vm = &self.session.as_ref().ok_or_else(not_launched)?.vm;
+        let inst = &vm.disassemble(vm.pc(), 1)[0];
+
+        match OpCode::from_u8(inst.bytes[0]) {
+            Some(OpCode::CALLA) | Some(OpCode::CALLR) => {
+                let addr = vm.pc().wrapping_add(inst.bytes.len() as u16);
+                let sp = vm.sp();
+                self.resume(RunMode::Over { addr, sp })
+            }
+            _ => self.step_in(),
+        }
+    }
+
+    fn step_in(&mut self) -> Result<Value, String> {
+        let session = self.session.as_mut().ok_or_else(not_launched)?;
+        if session.crashed {
+            self.finish(1);
+            return Ok(json!({}));
+        }
+
+        // Stopping on a breakpoint before executing anything isn't a step
+        let pc = session.vm.pc();
+        let res = match session.vm.step() {
+            Ok(StepOutcome::Breakpoint(addr)) if addr == pc => session.vm.step(),
+            res => res,
+        };
+
+        match res {
+            Ok(StepOutcome::Halted) => self.finish(0),
+            Ok(_) => self.stop("step", None),
+            Err(e) => self.crash(e),
+        }
+        Ok(json!({}))
+    }
+
+    // Run up to POLL_STEPS instructions
+    fn run_slice(&mut self, mode: RunMode) {
+        let vm = match &mut self.session {
+            Some(session) => &mut session.vm,
+            None => return,
+        };
+
+        for _ in 0..POLL_STEPS {
+            let returning = matches!(
+                OpCode::from_u8(vm.peek(vm.pc())),
+                Some(OpCode::RTN) | Some(OpCode::RTI)
+            );
+
+            let outcome = match vm.step() {
+                Ok(outcome) => outcome,
+                Err(e) => return self.crash(e),
+            };
+
+            match outcome {
+                StepOutcome::Halted => return self.finish(0),
+                StepOutcome::Breakpoint(_) => return self.stop("breakpoint", None),
+                StepOutcome::Debug => return self.stop("breakpoint", Some("DEBUG instruction")),
+                StepOutcome::Watchpoint(_) => return self.stop("data breakpoint", None),
+                StepOutcome::Continue | StepOutcome::DeviceWrite(_) => {}
+            }
+
+            let done = match mode {
+                RunMode::Continue => false,
+                RunMode::Over { addr, sp } => vm.pc() == addr && vm.sp() >= sp,
+                RunMode::Out { sp } => returning && vm.sp() > sp,
+            };
+            if done {
+                return self.stop("step", None);
+            }
+        }
+    }
+
+    fn stop(&mut self, reason: &str, description: Option<&str>) {
+        self.running = None;
+        self.client.borrow_mut().flush_output();
+
+        let mut body = json!({
+            "reason": reason,
+            "threadId": THREAD_ID,
+            "allThreadsStopped": true,
+        });
+        if let Some(description) = description {
+            body["description"] = json!(description);
+        }
+        self.events.push(("stopped", body));
+    }
+
+    // The machine state is kept so it can be inspected, continuing ends the
+    // session
+    fn crash(&mut self, e: VmError) {
+        if let Some(session) = &mut self.session {
+            session.crashed = true;
+        }
+        self.running = None;
+
+        let mut client = self.client.borrow_mut();
+        client.flush_output();
+        client.event(
+            "output",
+            json!({"category": "stderr", "output": format!("VM crashed: {}\n", e)}),
+        );
+        self.events.push((
+            "stopped",
+            json!({
+                "reason": "exception",
+                "description": "VM crashed",
+                "text": e.to_string(),
+                "threadId": THREAD_ID,
+                "allThreadsStopped": true,
+            }),
+        ));
+    }
+
+    // The session ends with the program, later requests fail
+    fn finish(&mut self, exit_code: i32) {
+        self.running = None;
+        self.session = None;
+        self.client.borrow_mut().flush_output();
+
+        self.events.push(("exited", json!({"exitCode": exit_code})));
+        self.events.push(("terminated", json!({})));
+    }
+}
+
+fn not_launched() -> String {
+    "No program has been launched".to_owned()
+}
+
+fn same_path(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use asml_vm::devices::OutputBuffer;
+
+    const PROGRAM: &str = "LDSP #0xFF
+LOAD %1 #\"H\"
+CALL print
+LOAD %1 #\"i\"
+CALL print
+HALT
+
+:print
+    STR %1 0xFFFD
+    RTN
+";
+
+    // Run the requests through an adapter and return the messages it sent
+    fn session(requests: Vec<Value>) -> Vec<Value> {
+        let (tx, rx) = mpsc::channel();
+        for (seq, req) in requests.into_iter().enumerate() {
+            let mut req = req;
+            req["type"] = json!("request");
+            req["seq"] = json!(seq + 1);
+            tx.send(req).unwrap();
+        }
+        drop(tx);
+
+        let out = OutputBuffer::new();
+        Adapter::new(rx, Box::new(out.clone())).run();
+
+        let out = out.contents();
+        let mut input = out.as_bytes();
+        let mut messages = Vec::new();
+        while let Some(msg) = read_message(&mut input).unwrap() {
+            messages.push(msg);
+        }
+        messages
+    }
+
+    fn events<'a>(messages: &'a [Value], event: &str) -> Vec<&'a Value> {
+        messages.iter().filter(|m| m["event"] == event).collect()
+    }
+
+    #[test]
+    fn test_session() {
+        let path = std::env::temp_dir().join(format!("asml-dap-{}.asml", std::process::id()));
+        fs::write(&path, PROGRAM).unwrap();
+
+        let messages = session(vec![
+            json!({"command": "initialize", "arguments": {}}),
+            json!({"command": "launch", "arguments": {"program": path}}),
+            json!({"command": "setBreakpoints", "arguments": {
+                "source": {"path": path},
+                "breakpoints": [{"line": 8}, {"line": 20}],
+            }}),
+            json!({"command": "configurationDone"}),
+            json!({"command": "stackTrace", "arguments": {"threadId": 1}}),
+            json!({"command": "variables", "arguments": {"variablesReference": REGISTERS_REF}}),
+            json!({"command": "variables", "arguments": {"variablesReference": MEMORY_PAGE_REF + 0xFF}}),
+            json!({"command": "continue", "arguments": {"threadId": 1}}),
+            json!({"command": "stepOut", "arguments": {"threadId": 1}}),
+            json!({"command": "next", "arguments": {"threadId": 1}}),
+            json!({"command": "disconnect"}),
+        ]);
+        fs::remove_file(&path).unwrap();
+
+        let responses: Vec<&Value> = messages
+            .iter()
+            .filter(|m| m["type"] == "response")
+            .collect();
+        assert_eq!(responses.len(), 11);
+        assert!(
+            responses.iter().all(|r| r["success"] == true),
+            "{:?}",
+            responses
+        );
+
+        // Line 8 is the label, the breakpoint moves to the instruction after it
+        let bps = &responses[2]["body"]["breakpoints"];
+        assert_eq!(bps[0], json!({"verified": true, "line": 9}));
+        assert_eq!(bps[1]["verified"], false);
+
+        let frame = &responses[4]["body"]["stackFrames"][0];
+        assert_eq!(frame["name"], "print");
+        assert_eq!(frame["line"], 9);
+
+        let registers = responses[5]["body"]["variables"].as_array().unwrap();
+        assert_eq!(registers[1]["name"], "%1");
+        assert_eq!(registers[1]["value"], "0x48");
+
+        // The last row of the last page ends at 0xFFFF
+        let rows = responses[6]["body"]["variables"].as_array().unwrap();
+        assert_eq!(rows.len(), 16);
+        assert_eq!(rows[15]["name"], "0xFFF0");
+        let bytes = rows[15]["value"].as_str().unwrap().split(' ').count();
+        assert_eq!(bytes, 16);
+
+        let reasons: Vec<&Value> = events(&messages, "stopped")
+            .iter()
+            .map(|e| &e["body"]["reason"])
+            .collect();
+        assert_eq!(reasons, ["breakpoint", "breakpoint", "step"]);
+
+        let output: Vec<&Value> = events(&messages, "output")
+            .iter()
+            .map(|e| &e["body"]["output"])
+            .collect();
+        assert_eq!(output, ["H", "i"]);
+
+        assert_eq!(events(&messages, "exited")[0]["body"]["exitCode"], 0);
+        assert_eq!(events(&messages, "terminated").len(), 1);
+    }
+}
@@ -1,12 +1,22 @@
 extern crate clap;
 
 mod compiler;
+mod dap;
 
+use std::cell::RefCell;
 use std::fs::File;
-use std::io::Write;
+use std::io::{BufReader, BufWriter, IsTerminal, Write};
+use std::ops::RangeInclusive;
 use std::path::Path;
+use std::rc::Rc;
+use std::time::Duration;
 
-use clap::{App, AppSettings, Arg, SubCommand};
+use asml_vm::debugger::Debugger;
+use asml_vm::devices::{Disk, Display, Keyboard, MAX_BANKS, Uart};
+use asml_vm::gdb::{self, SessionEnd};
+use asml_vm::protection::{AddrRange, Region};
+use asml_vm::symbols::SymbolTable;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
 
 const ASML_HEADER: &str = "ASML";
 
@@ -15,6 +25,7 @@ fn main() {
         .version("0.1.0")
         .author("Lee Keitel")
         .arg(Arg::with_name("INPUT").required(true))
+        .args(&vm_args())
         .subcommand(
             SubCommand::with_name("compile")
                 .about("Compile an ASML file to srecord format")
@@ -24,8 +35,24 @@ fn main() {
         .subcommand(
             SubCommand::with_name("run")
                 .about("Compile and execute an ASML file")
+                .arg(Arg::with_name("INPUT").required(true))
+                .args(&vm_args()),
+        )
+        .subcommand(
+            SubCommand::with_name("disasm")
+                .about("Disassemble an srecord file to ASML source")
                 .arg(Arg::with_name("INPUT").required(true)),
         )
+        .subcommand(
+            SubCommand::with_name("dap")
+                .about("Run a Debug Adapter Protocol server on stdin and stdout"),
+        )
+        .subcommand(
+            SubCommand::with_name("resume")
+                .about("Resume execution from a VM snapshot")
+                .arg(Arg::with_name("SNAPSHOT").required(true))
+                .args(&vm_args()),
+        )
         .settings(&[
             AppSettings::ArgsNegateSubcommands,
             AppSettings::SubcommandsNegateReqs,
@@ -38,20 +65,97 @@ fn main() {
             subcmd.value_of("output").unwrap(),
         );
     } else if let Some(subcmd) = app.subcommand_matches("run") {
-        run_file(subcmd.value_of("INPUT").unwrap());
+        run_file(subcmd.value_of("INPUT").unwrap(), subcmd);
+    } else if let Some(subcmd) = app.subcommand_matches("disasm") {
+        let code = load_srecord(subcmd.value_of("INPUT").unwrap());
+        print!("{}", asml_vm::disasm::disassemble_code(&code));
+    } else if app.subcommand_matches("dap").is_some() {
+        dap::serve_stdio();
+    } else if let Some(subcmd) = app.subcommand_matches("resume") {
+        resume_snapshot(subcmd.value_of("SNAPSHOT").unwrap(), subcmd);
     } else {
-        exec_srecord(app.value_of("INPUT").unwrap());
+        exec_srecord(app.value_of("INPUT").unwrap(), &app);
     }
 }
 
+// Arguments shared by every command that executes code
+fn vm_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("input_file")
+            .long("input")
+            .value_name("FILE")
+            .help("Read keyboard input from a file instead of stdin"),
+        Arg::with_name("break")
+            .long("break")
+            .value_name("ADDR")
+            .multiple(true)
+            .number_of_values(1)
+            .help("Set a breakpoint at a hex address before running"),
+        Arg::with_name("banks")
+            .long("banks")
+            .value_name("N")
+            .help("Switch the memory at 0x8000-0xBFFF between N banks"),
+        Arg::with_name("display")
+            .long("display")
+            .help("Attach a 40x25 text display with its memory at 0xF000"),
+        Arg::with_name("disk")
+            .long("disk")
+            .value_name("FILE")
+            .help("Attach a disk controller using a file as the disk image"),
+        Arg::with_name("serial")
+            .long("serial")
+            .value_name("PORT")
+            .help("Attach a serial port that uses listen:ADDR, connect:ADDR or pty"),
+        Arg::with_name("rom")
+            .long("rom")
+            .value_name("RANGE")
+            .multiple(true)
+            .number_of_values(1)
+            .help("Make a hex address range such as 0000-00FF read-only"),
+        Arg::with_name("no_exec")
+            .long("no-exec")
+            .value_name("RANGE")
+            .multiple(true)
+            .number_of_values(1)
+            .help("Stop with an error when executing code in a hex address range"),
+        Arg::with_name("debug_script")
+            .long("debug-script")
+            .value_name("FILE")
+            .help("Read debugger commands from a file before reading stdin"),
+        Arg::with_name("gdb")
+            .long("gdb")
+            .value_name("ADDR")
+            .help("Wait for a GDB remote connection on an address such as 127.0.0.1:1234"),
+        Arg::with_name("trace")
+            .long("trace")
+            .value_name("FILE")
+            .help("Write a trace of every executed instruction to a file"),
+        Arg::with_name("max_steps")
+            .long("max-steps")
+            .value_name("N")
+            .help("Stop with an error after executing N instructions"),
+        Arg::with_name("max_time")
+            .long("max-time")
+            .value_name("SECONDS")
+            .help("Stop with an error after running for a number of seconds"),
+        Arg::with_name("profile")
+            .long("profile")
+            .help("Print hot addresses, instruction counts and unexecuted code after running"),
+        Arg::with_name("coverage")
+            .long("coverage")
+            .value_name("FILE")
+            .help("Write how many times each range of code was executed to a file"),
+    ]
+}
+
 fn compile_file(path: &str, output: &str) {
     println!("Compiling {}", path);
     let src_path = Path::new(path);
-    let code = compiler::compile_file(src_path).unwrap_or_else(|e| {
+    let compiled = compiler::compile_file(src_path).unwrap_or_else(|e| {
         eprintln!("{}", e);
         std::process::exit(1);
     });
-    write_code_to_file(&code, output);
+    write_code_to_file(&compiled.code, output);
 }
 
 fn write_code_to_file(code: &[asml_vm::CodeSection], output: &str) {
@@ -65,15 +169,26 @@ fn write_code_to_file(code: &[asml_vm::CodeSection], output: &str) {
         let mut pc = part.org;
         let mut i = 0;
 
+        // Code in other banks uses 24 bit addresses with the bank number as the
+        // high byte
+        let mut add_record = |pc: u16, data: &[u8]| {
+            if part.bank == 0 {
+                records.add_record16(srecord::SrecType::SrecData16, pc, data);
+            } else {
+                let addr = (u32::from(part.bank) << 16) | u32::from(pc);
+                records.add_record32(srecord::SrecType::SrecData24, addr, data);
+            }
+        };
+
         while total_len > 252 {
-            records.add_record16(srecord::SrecType::SrecData16, pc, &part.code[i..i + 252]);
+            add_record(pc, &part.code[i..i + 252]);
             pc += 252;
             i += 252;
             total_len -= 252;
             line_count += 1;
         }
 
-        records.add_record16(srecord::SrecType::SrecData16, pc, &part.code[i..]);
+        add_record(pc, &part.code[i..]);
         line_count += 1;
     }
 
@@ -94,17 +209,23 @@ fn write_code_to_file(code: &[asml_vm::CodeSection], output: &str) {
     }}}
 }
 
-fn run_file(path: &str) {
+fn run_file(path: &str, args: &ArgMatches) {
     println!("Compiling {}", path);
     let src_path = Path::new(path);
-    let code = compiler::compile_file(src_path).unwrap_or_else(|e| {
+    let compiled = compiler::compile_file(src_path).unwrap_or_else(|e| {
         eprintln!("{}", e);
         std::process::exit(1);
     });
-    execute_code(&code);
+    execute_code(&compiled.code, Some(&compiled.symbols), args);
+}
+
+fn exec_srecord(path: &str, args: &ArgMatches) {
+    let code = load_srecord(path);
+    execute_code(&code, None, args);
 }
 
-fn exec_srecord(path: &str) {
+// Read the data records of an srecord file as code sections
+fn load_srecord(path: &str) -> Vec<asml_vm::CodeSection> {
     let srec_path = Path::new(path);
 
     let records = srecord::parse_file(srec_path).unwrap_or_else(|e| {
@@ -115,20 +236,273 @@ fn exec_srecord(path: &str) {
     let mut code = Vec::new();
 
     for r in records.0 {
-        code.push(asml_vm::CodeSection {
-            org: r.address as u16,
-            code: r.data,
-        });
+        match r.rec_type {
+            srecord::SrecType::SrecData16
+            | srecord::SrecType::SrecData24
+            | srecord::SrecType::SrecData32 => {
+                // Bits above the 16 bit address are the memory bank
+                if r.address >> 16 > u32::from(u8::MAX) {
+                    eprintln!(
+                        "Record address 0x{:X} is past the last memory bank",
+                        r.address
+                    );
+                    std::process::exit(1);
+                }
+
+                code.push(asml_vm::CodeSection {
+                    org: r.address as u16,
+                    bank: (r.address >> 16) as u8,
+                    code: r.data,
+                });
+            }
+            _ => {}
+        }
+    }
+
+    code
+}
+
+fn resume_snapshot(path: &str, args: &ArgMatches) {
+    let mut vm = asml_vm::VM::new();
+
+    let res = match File::open(path) {
+        Ok(file) => vm.load_snapshot(&mut BufReader::new(file)),
+        Err(e) => Err(e),
+    };
+    if let Err(e) = res {
+        eprintln!("Unable to load snapshot {}: {}", path, e);
+        std::process::exit(1);
     }
 
-    execute_code(&code);
+    run_vm(vm, &[], None, args);
 }
 
-fn execute_code(code: &[asml_vm::CodeSection]) {
+fn execute_code(code: &[asml_vm::CodeSection], symbols: Option<&SymbolTable>, args: &ArgMatches) {
     let mut vm = asml_vm::VM::new();
     vm.install_code(code);
+    run_vm(vm, code, symbols, args);
+}
+
+// The code and symbols are only used for profiling reports. A resumed
+// snapshot has neither so its coverage report is empty.
+fn run_vm(
+    mut vm: asml_vm::VM,
+    code: &[asml_vm::CodeSection],
+    symbols: Option<&SymbolTable>,
+    args: &ArgMatches,
+) {
+    let keyboard = match args.value_of("input_file") {
+        Some(path) => match File::open(path) {
+            Ok(file) => Keyboard::new(Box::new(file)),
+            Err(e) => {
+                eprintln!("Unable to open input file {}: {}", path, e);
+                std::process::exit(1);
+            }
+        },
+        None => Keyboard::stdin(),
+    };
+    vm.attach_device(Box::new(keyboard));
+    vm.set_output(Box::new(std::io::stdout()));
+
+    if let Some(path) = args.value_of("disk") {
+        match Disk::open(path) {
+            Ok(disk) => vm.attach_device(Box::new(disk)),
+            Err(e) => {
+                eprintln!("Unable to open disk image {}: {}", path, e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if let Some(port) = args.value_of("serial") {
+        match open_serial(port) {
+            Ok(uart) => {
+                eprintln!("Serial port on {}", uart.endpoint());
+                vm.attach_device(Box::new(uart));
+            }
+            Err(e) => {
+                eprintln!("Unable to open serial port {}: {}", port, e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // The display is drawn live on a terminal, otherwise its final contents
+    // are printed after the run
+    let display = args.is_present("display").then(|| {
+        let display = if std::io::stdout().is_terminal() {
+            Display::with_terminal(Box::new(std::io::stdout()))
+        } else {
+            Display::new()
+        };
+        let display = Rc::new(RefCell::new(display));
+        vm.attach_device(Box::new(display.clone()));
+        display
+    });
+
+    let mut debugger = Debugger::new();
+    if let Some(symbols) = symbols {
+        debugger.set_symbols(symbols.clone());
+    }
+    if let Some(path) = args.value_of("debug_script") {
+        match File::open(path) {
+            Ok(file) => debugger.set_script(Box::new(BufReader::new(file))),
+            Err(e) => {
+                eprintln!("Unable to open debugger script {}: {}", path, e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    for addr in args.values_of("break").into_iter().flatten() {
+        match debugger.parse_location(addr) {
+            Some(addr) => vm.add_breakpoint(addr, None),
+            None => {
+                eprintln!("Invalid breakpoint address {}", addr);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if let Some(n) = args.value_of("banks") {
+        match n.parse::<usize>() {
+            Ok(n) if (1..=MAX_BANKS).contains(&n) => vm.enable_banking(n),
+            _ => {
+                eprintln!("Invalid number of banks {}, must be 1 to {}", n, MAX_BANKS);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    for range in args.values_of("rom").into_iter().flatten() {
+        vm.protect(Region::rom(parse_range(range)));
+    }
+    for range in args.values_of("no_exec").into_iter().flatten() {
+        vm.protect(Region::no_execute(parse_range(range)));
+    }
+
+    if let Some(path) = args.value_of("trace") {
+        match File::create(path) {
+            Ok(file) => vm.set_trace(Box::new(BufWriter::new(file))),
+            Err(e) => {
+                eprintln!("Unable to create trace file {}: {}", path, e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if let Some(n) = args.value_of("max_steps") {
+        match n.parse::<u64>() {
+            Ok(n) => vm.set_max_steps(Some(n)),
+            Err(_) => {
+                eprintln!("Invalid instruction limit {}", n);
+                std::process::exit(1);
+            }
+        }
+    }
 
-    if vm.run().is_ok() {
-        println!("{}", vm.output());
+    if let Some(secs) = args.value_of("max_time") {
+        match secs
+            .parse::<f64>()
+            .ok()
+            .and_then(|s| Duration::try_from_secs_f64(s).ok())
+        {
+            Some(t) => vm.set_max_time(Some(t)),
+            None => {
+                eprintln!("Invalid time limit {}", secs);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if args.is_present("profile") || args.is_present("coverage") {
+        vm.enable_profile();
+    }
+
+    let res = match args.value_of("gdb") {
+        Some(addr) => run_gdb(&mut vm, addr),
+        None => debugger.run(&mut vm),
+    };
+    if let Err(e) = vm.finish_trace() {
+        eprintln!("Unable to write trace file: {}", e);
+    }
+
+    if let Some(profile) = vm.profile() {
+        if args.is_present("profile")
+            && let Err(e) = profile.write_report(&mut std::io::stderr(), code, symbols)
+        {
+            eprintln!("Unable to write profile: {}", e);
+        }
+
+        if let Some(path) = args.value_of("coverage") {
+            let res = File::create(path).and_then(|file| {
+                let mut out = BufWriter::new(file);
+                profile.write_coverage(&mut out, code)?;
+                out.flush()
+            });
+            if let Err(e) = res {
+                eprintln!("Unable to write coverage file {}: {}", path, e);
+            }
+        }
+    }
+
+    // Finish the last line of program output
+    println!();
+
+    if let Some(display) = display {
+        let mut display = display.borrow_mut();
+        if std::io::stdout().is_terminal() {
+            if let Err(e) = display.render() {
+                eprintln!("Unable to draw display: {}", e);
+            }
+        } else {
+            print!("{}", display.text());
+        }
+    }
+
+    if let Err(e) = res {
+        eprintln!("VM crashed: {}\n\n{}", e, e.state());
+        std::process::exit(1);
+    }
+}
+
+// The other end of the serial port, a TCP address to listen on or connect
+// to, or a new pty
+fn open_serial(port: &str) -> std::io::Result<Uart> {
+    match port.split_once(':') {
+        Some(("listen", addr)) => Uart::listen(addr),
+        Some(("connect", addr)) => Uart::connect(addr),
+        #[cfg(target_os = "linux")]
+        None if port == "pty" => Uart::pty(),
+        _ => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "expected listen:ADDR, connect:ADDR or pty",
+        )),
+    }
+}
+
+fn parse_range(s: &str) -> RangeInclusive<u16> {
+    match s.parse::<AddrRange>() {
+        Ok(AddrRange(range)) => range,
+        Err(e) => {
+            eprintln!("Invalid address range {}: {}", s, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+// Let a GDB client control the VM. If the client detaches the program runs
+// to the end.
+fn run_gdb(vm: &mut asml_vm::VM, addr: &str) -> Result<(), asml_vm::VmError> {
+    eprintln!("Waiting for GDB connection on {}", addr);
+
+    match gdb::serve(vm, addr) {
+        Ok(SessionEnd::Detached) => vm.run(),
+        Ok(SessionEnd::Crashed(e)) => Err(e),
+        Ok(_) => Ok(()),
+        Err(e) => {
+            eprintln!("GDB connection failed: {}", e);
+            std::process::exit(1);
+        }
     }
 }
@@ -0,0 +1,229 @@
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+/// Parse a debugger address. Addresses are always hex with an optional 0x
+/// prefix.
+pub fn parse_addr(s: &str) -> Option<u16> {
+    u16::from_str_radix(s.strip_prefix("0x").unwrap_or(s), 16).ok()
+}
+
+/// Parse a register name such as `%1` or `%A`.
+pub fn parse_register(s: &str) -> Option<u8> {
+    let r = u8::from_str_radix(s.strip_prefix('%')?, 16).ok()?;
+    if r <= 0xD { Some(r) } else { None }
+}
+
+/// Parse a debugger value. Values are decimal unless prefixed with 0x.
+pub fn parse_value(s: &str) -> Option<u16> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None => s.parse::<u16>().ok(),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CmpOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl CmpOp {
+    fn apply(self, a: u16, b: u16) -> bool {
+        match self {
+            CmpOp::Eq => a == b,
+            CmpOp::Ne => a != b,
+            CmpOp::Lt => a < b,
+            CmpOp::Le => a <= b,
+            CmpOp::Gt => a > b,
+            CmpOp::Ge => a >= b,
+        }
+    }
+}
+
+impl fmt::Display for CmpOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                CmpOp::Eq => "==",
+                CmpOp::Ne => "!=",
+                CmpOp::Lt => "<",
+                CmpOp::Le => "<=",
+                CmpOp::Gt => ">",
+                CmpOp::Ge => ">=",
+            }
+        )
+    }
+}
+
+/// A register comparison such as `%1 == 0`. Values are decimal unless
+/// prefixed with 0x.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Condition {
+    pub reg: u8,
+    pub op: CmpOp,
+    pub value: u16,
+}
+
+impl Condition {
+    pub fn matches(&self, reg_value: u16) -> bool {
+        self.op.apply(reg_value, self.value)
+    }
+}
+
+impl FromStr for Condition {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split_whitespace().collect();
+        if parts.len() != 3 {
+            return Err("condition must look like `%R == VALUE`".to_owned());
+        }
+
+        let reg =
+            parse_register(parts[0]).ok_or_else(|| format!("invalid register `{}`", parts[0]))?;
+
+        let op = match parts[1] {
+            "==" => CmpOp::Eq,
+            "!=" => CmpOp::Ne,
+            "<" => CmpOp::Lt,
+            "<=" => CmpOp::Le,
+            ">" => CmpOp::Gt,
+            ">=" => CmpOp::Ge,
+            op => return Err(format!("invalid comparison `{}`", op)),
+        };
+
+        let value = parse_value(parts[2]).ok_or_else(|| format!("invalid value `{}`", parts[2]))?;
+
+        Ok(Condition { reg, op, value })
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "%{:X} {} 0x{:X}", self.reg, self.op, self.value)
+    }
+}
+
+/// Stops execution before the instruction at `addr` is executed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Breakpoint {
+    pub addr: u16,
+    pub condition: Option<Condition>,
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "0x{:04X}", self.addr)?;
+        if let Some(cond) = &self.condition {
+            write!(f, " if {}", cond)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    Access,
+}
+
+impl WatchKind {
+    fn includes(self, other: WatchKind) -> bool {
+        self == WatchKind::Access || self == other
+    }
+}
+
+/// Stops execution after an instruction reads or writes memory in `range`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Watchpoint {
+    pub range: RangeInclusive<u16>,
+    pub kind: WatchKind,
+}
+
+impl Watchpoint {
+    pub(crate) fn matches(&self, addr: u16, kind: WatchKind) -> bool {
+        self.kind.includes(kind) && self.range.contains(&addr)
+    }
+}
+
+impl fmt::Display for Watchpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match self.kind {
+            WatchKind::Read => "read",
+            WatchKind::Write => "write",
+            WatchKind::Access => "access",
+        };
+        write!(
+            f,
+            "{} 0x{:04X}-0x{:04X}",
+            kind,
+            self.range.start(),
+            self.range.end()
+        )
+    }
+}
+
+/// A memory access that triggered a watchpoint.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WatchHit {
+    pub addr: u16,
+    pub kind: WatchKind,
+    pub value: u8,
+}
+
+impl fmt::Display for WatchHit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match self.kind {
+            WatchKind::Write => "write to",
+            _ => "read from",
+        };
+        write!(f, "{} 0x{:04X} = 0x{:02X}", kind, self.addr, self.value)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_condition() {
+        let cond: Condition = "%1 == 0".parse().unwrap();
+        assert_eq!(
+            cond,
+            Condition {
+                reg: 1,
+                op: CmpOp::Eq,
+                value: 0
+            }
+        );
+
+        let cond: Condition = "%A >= 0x100".parse().unwrap();
+        assert_eq!(cond.reg, 0xA);
+        assert!(cond.matches(0x100));
+        assert!(!cond.matches(0xFF));
+
+        assert!("%E == 0".parse::<Condition>().is_err());
+        assert!("%1 = 0".parse::<Condition>().is_err());
+        assert!("%1 ==".parse::<Condition>().is_err());
+    }
+
+    #[test]
+    fn test_watchpoint_matches() {
+        let wp = Watchpoint {
+            range: 0x40..=0x4F,
+            kind: WatchKind::Write,
+        };
+
+        assert!(wp.matches(0x40, WatchKind::Write));
+        assert!(!wp.matches(0x40, WatchKind::Read));
+        assert!(!wp.matches(0x50, WatchKind::Write));
+    }
+}
@@ -0,0 +1,664 @@
+//! Interactive debugger.
+//!
+//! The debugger runs a `VM` and opens a prompt whenever execution stops on a
+//! DEBUG instruction, breakpoint or watchpoint. Commands are read from a
+//! script first, if one is given, and then from the debugger's input. The
+//! commands are described in `docs/debugging.md`.
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, StdinLock, Write};
+
+use crate::breakpoints::{Condition, WatchKind, parse_addr, parse_register, parse_value};
+use crate::disasm::Line;
+use crate::symbols::SymbolTable;
+use crate::{
+    FLAG_CARRY, FLAG_INTERRUPT, FLAG_NEGATIVE, FLAG_OVERFLOW, FLAG_ZERO, StepOutcome, VM, VmError,
+};
+
+const PROMPT: &str = "Debug> ";
+
+// Lines shown on each side of PC by `list`
+const LIST_LINES: usize = 4;
+
+// How far back `list` looks for a label to start disassembling from
+const LIST_LABEL_RANGE: u16 = 64;
+
+enum Action {
+    Prompt,
+    Step,
+    Continue,
+    Quit,
+}
+
+// Commands from stdin. The lock is only held while a command is read, so the
+// keyboard device shares stdin and its buffer with the debugger.
+struct StdinInput(Option<StdinLock<'static>>);
+
+impl Read for StdinInput {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0 = None;
+        io::stdin().lock().read(buf)
+    }
+}
+
+impl BufRead for StdinInput {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.0.get_or_insert_with(|| io::stdin().lock()).fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        if let Some(mut lock) = self.0.take() {
+            lock.consume(amt);
+        }
+    }
+
+    fn read_line(&mut self, buf: &mut String) -> io::Result<usize> {
+        self.0 = None;
+        io::stdin().lock().read_line(buf)
+    }
+}
+
+pub struct Debugger {
+    input: Box<dyn BufRead>,
+    script: Option<Box<dyn BufRead>>,
+    out: Box<dyn Write>,
+    symbols: SymbolTable,
+    history: Vec<String>,
+    debug_disabled: bool,
+    // Set once the input ends, after which the program runs without stopping
+    detached: bool,
+}
+
+impl Default for Debugger {
+    fn default() -> Self {
+        Debugger::new()
+    }
+}
+
+impl Debugger {
+    /// A debugger reading commands from stdin and writing to stdout.
+    pub fn new() -> Self {
+        Debugger::with_io(Box::new(StdinInput(None)), Box::new(io::stdout()))
+    }
+
+    pub fn with_io(input: Box<dyn BufRead>, out: Box<dyn Write>) -> Self {
+        Debugger {
+            input,
+            script: None,
+            out,
+            symbols: SymbolTable::new(),
+            history: Vec::new(),
+            debug_disabled: false,
+            detached: false,
+        }
+    }
+
+    /// Use labels for addresses in commands and output.
+    pub fn set_symbols(&mut self, symbols: SymbolTable) {
+        self.symbols = symbols;
+    }
+
+    /// Run the commands in `script` before reading from the input. Commands
+    /// are echoed after the prompt as if they had been typed.
+    pub fn set_script(&mut self, script: Box<dyn BufRead>) {
+        self.script = Some(script);
+    }
+
+    /// Commands entered so far, oldest first.
+    pub fn history(&self) -> &[String] {
+        &self.history
+    }
+
+    /// Run the machine until it halts or the `quit` command is given.
+    pub fn run(&mut self, vm: &mut VM) -> Result<(), VmError> {
+        let mut paused = false;
+
+        loop {
+            if paused && !self.detached {
+                let action = match self.prompt(vm) {
+                    Ok(action) => action,
+                    Err(_) => {
+                        self.detached = true;
+                        Action::Continue
+                    }
+                };
+
+                match action {
+                    Action::Quit => return Ok(()),
+                    Action::Continue => paused = false,
+                    _ => {}
+                }
+            }
+
+            let outcome = if paused && !self.detached {
+                vm.step()?
+            } else {
+                vm.resume()?
+            };
+
+            // Output errors end the session the same as the end of input
+            let res = match outcome {
+                StepOutcome::Halted => return Ok(()),
+                StepOutcome::Debug if self.debug_disabled => Ok(()),
+                StepOutcome::Debug if !paused => {
+                    paused = true;
+                    writeln!(self.out, "Paused at {}", self.fmt_addr(vm.pc()))
+                }
+                StepOutcome::Breakpoint(addr) => {
+                    paused = true;
+                    writeln!(self.out, "Breakpoint hit at {}", self.fmt_addr(addr))
+                }
+                StepOutcome::Watchpoint(hit) => {
+                    paused = true;
+                    writeln!(self.out, "Watchpoint hit: {}", hit)
+                }
+                _ => Ok(()),
+            };
+            if paused && !self.detached && res.and_then(|_| self.show_pc(vm)).is_err() {
+                self.detached = true;
+            }
+        }
+    }
+
+    // Read the next command, from the script until it runs out
+    fn read_command(&mut self) -> io::Result<Option<String>> {
+        write!(self.out, "{}", PROMPT)?;
+        self.out.flush()?;
+
+        let mut line = String::new();
+        if let Some(script) = &mut self.script {
+            if script.read_line(&mut line)? > 0 {
+                write!(self.out, "{}", line)?;
+                if !line.ends_with('\n') {
+                    writeln!(self.out)?;
+                }
+                return Ok(Some(line));
+            }
+            self.script = None;
+        }
+
+        if self.input.read_line(&mut line)? == 0 {
+            writeln!(self.out)?;
+            return Ok(None);
+        }
+        Ok(Some(line))
+    }
+
+    fn prompt(&mut self, vm: &mut VM) -> io::Result<Action> {
+        loop {
+            let line = match self.read_command()? {
+                Some(line) => line,
+                None => {
+                    // Nothing left to read so let the program finish
+                    self.detached = true;
+                    return Ok(Action::Continue);
+                }
+            };
+
+            let mut line = line.trim().to_owned();
+            if line.starts_with('#') {
+                continue;
+            }
+
+            // An empty line repeats the last command and !N repeats command N
+            if line.is_empty() {
+                match self.history.last() {
+                    Some(last) => line = last.clone(),
+                    None => continue,
+                }
+            } else if let Some(n) = line.strip_prefix('!') {
+                match n.parse::<usize>().ok().and_then(|n| self.history.get(n)) {
+                    Some(cmd) => {
+                        line = cmd.clone();
+                        writeln!(self.out, "{}", line)?;
+                    }
+                    None => {
+                        writeln!(self.out, "No command {} in history", n)?;
+                        continue;
+                    }
+                }
+            }
+
+            if self.history.last() != Some(&line) {
+                self.history.push(line.clone());
+            }
+
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            match self.command(vm, &parts)? {
+                Action::Prompt => {}
+                action => return Ok(action),
+            }
+        }
+    }
+
+    fn command(&mut self, vm: &mut VM, parts: &[&str]) -> io::Result<Action> {
+        let args = &parts[1..];
+
+        match parts[0] {
+            "step" => return Ok(Action::Step),
+            "continue" | "con" => return Ok(Action::Continue),
+            "exit" | "quit" => return Ok(Action::Quit),
+            "memory" | "mem" => self.memory(vm, args)?,
+            "disable" | "dis" => self.debug_disabled = true,
+            "enable" | "en" => self.debug_disabled = false,
+            "next" => {
+                let line = &vm.disassemble(vm.pc(), 1)[0];
+                writeln!(self.out, "Next Instruction: {}", line)?;
+            }
+            "list" | "l" => self.list(vm)?,
+            "registers" | "reg" => self.registers(vm)?,
+            "set" => self.set(vm, args)?,
+            "poke" => self.poke(vm, args)?,
+            "bank" => match (args.first(), vm.bank()) {
+                (_, None) => writeln!(self.out, "Banking is not enabled")?,
+                (None, Some(bank)) => writeln!(self.out, "Bank {} of {}", bank, vm.bank_count())?,
+                (Some(n), Some(_)) => match parse_value(n) {
+                    Some(n) if usize::from(n) < vm.bank_count() => vm.select_bank(n as u8),
+                    _ => writeln!(self.out, "Usage: bank [0-{}]", vm.bank_count() - 1)?,
+                },
+            },
+            "back" => match args.first().map_or(Ok(1), |n| n.parse::<usize>()) {
+                Ok(count) => {
+                    let undone = (0..count).take_while(|_| vm.step_back()).count();
+                    writeln!(
+                        self.out,
+                        "Stepped back {} instructions to {}",
+                        undone,
+                        self.fmt_addr(vm.pc())
+                    )?;
+                }
+                Err(_) => writeln!(self.out, "Usage: back [N]")?,
+            },
+            "reverse-continue" | "rc" => match vm.reverse_continue() {
+                Ok(Some(addr)) => writeln!(self.out, "Breakpoint hit at {}", self.fmt_addr(addr))?,
+                Ok(None) => writeln!(
+                    self.out,
+                    "Reached start of history at {}",
+                    self.fmt_addr(vm.pc())
+                )?,
+                Err(e) => writeln!(self.out, "{}", e)?,
+            },
+            "break" | "b" => self.add_break(vm, args)?,
+            "delete" | "del" => match args.first().and_then(|a| self.parse_location(a)) {
+                Some(addr) if vm.remove_breakpoint(addr) => {}
+                _ => writeln!(self.out, "No breakpoint at that address")?,
+            },
+            "watch" => self.add_watch(vm, args, WatchKind::Write)?,
+            "rwatch" => self.add_watch(vm, args, WatchKind::Read)?,
+            "awatch" => self.add_watch(vm, args, WatchKind::Access)?,
+            "unwatch" => match args.first().and_then(|i| i.parse::<usize>().ok()) {
+                Some(i) if vm.remove_watchpoint(i).is_some() => {}
+                _ => writeln!(self.out, "No such watchpoint")?,
+            },
+            "breakpoints" | "bps" => {
+                for bp in vm.breakpoints() {
+                    writeln!(self.out, "break {}", bp)?;
+                }
+                for (i, wp) in vm.watchpoints().iter().enumerate() {
+                    writeln!(self.out, "{}: watch {}", i, wp)?;
+                }
+            }
+            "history" => {
+                for (i, cmd) in self.history.iter().enumerate() {
+                    writeln!(self.out, "{:>4}  {}", i, cmd)?;
+                }
+            }
+            "printer" | "print" => writeln!(self.out, "{}", vm.output())?,
+            "save" => match args.first() {
+                Some(path) => match File::create(path) {
+                    Ok(file) => {
+                        if let Err(e) = vm.save_snapshot(&mut BufWriter::new(file)) {
+                            writeln!(self.out, "Failed to save snapshot: {}", e)?;
+                        }
+                    }
+                    Err(e) => writeln!(self.out, "Unable to create {}: {}", path, e)?,
+                },
+                None => writeln!(self.out, "Usage: save FILE")?,
+            },
+            "load" => match args.first() {
+                Some(path) => match File::open(path) {
+                    Ok(file) => match vm.load_snapshot(&mut BufReader::new(file)) {
+                        Ok(_) => {
+                            writeln!(self.out, "Loaded snapshot at {}", self.fmt_addr(vm.pc()))?
+                        }
+                        Err(e) => writeln!(self.out, "Failed to load snapshot: {}", e)?,
+                    },
+                    Err(e) => writeln!(self.out, "Unable to open {}: {}", path, e)?,
+                },
+                None => writeln!(self.out, "Usage: load FILE")?,
+            },
+            cmd => writeln!(self.out, "Unknown command `{}`", cmd)?,
+        }
+        Ok(Action::Prompt)
+    }
+
+    /// Parse an address as a label, a label with a decimal offset such as
+    /// `loop+4`, or a hex address.
+    pub fn parse_location(&self, s: &str) -> Option<u16> {
+        if let Some(addr) = self.symbols.lookup(s) {
+            return Some(addr);
+        }
+
+        if let Some((name, offset)) = s.split_once('+')
+            && let Some(addr) = self.symbols.lookup(name)
+        {
+            return addr.checked_add(offset.parse().ok()?);
+        }
+
+        parse_addr(s)
+    }
+
+    fn fmt_addr(&self, addr: u16) -> String {
+        match self.symbols.resolve(addr) {
+            Some(label) => format!("0x{:04X} <{}>", addr, label),
+            None => format!("0x{:04X}", addr),
+        }
+    }
+
+    fn show_pc(&mut self, vm: &VM) -> io::Result<()> {
+        let line = &vm.disassemble(vm.pc(), 1)[0];
+        writeln!(self.out, "=> {}", line)
+    }
+
+    // mem [START [END]]
+    fn memory(&mut self, vm: &VM, args: &[&str]) -> io::Result<()> {
+        let start = args.first().map(|a| self.parse_location(a));
+        let end = args.get(1).map(|a| self.parse_location(a));
+
+        match (start, end) {
+            (None, None) => self.dump(vm, 0, 0xFF),
+            (Some(Some(addr)), None) => {
+                writeln!(self.out, "[{:04X}] = 0x{:02X}", addr, vm.peek(addr))
+            }
+            (Some(Some(start)), Some(Some(end))) if start <= end => self.dump(vm, start, end),
+            _ => writeln!(self.out, "Usage: mem [START [END]]"),
+        }
+    }
+
+    // Print memory 16 bytes per line with the printable characters
+    fn dump(&mut self, vm: &VM, start: u16, end: u16) -> io::Result<()> {
+        let bytes: Vec<u8> = (start..=end).map(|addr| vm.peek(addr)).collect();
+
+        for (i, row) in bytes.chunks(16).enumerate() {
+            let hex: Vec<String> = row.iter().map(|b| format!("{:02X}", b)).collect();
+            let text: String = row
+                .iter()
+                .map(|b| match b {
+                    0x20..=0x7E => *b as char,
+                    _ => '.',
+                })
+                .collect();
+
+            writeln!(
+                self.out,
+                "{:04X}  {:<47}  |{}|",
+                usize::from(start) + i * 16,
+                hex.join(" "),
+                text
+            )?;
+        }
+        Ok(())
+    }
+
+    fn registers(&mut self, vm: &VM) -> io::Result<()> {
+        writeln!(self.out, "0    1    2    3    4    5    6    7    8    9")?;
+        for reg in vm.registers() {
+            write!(self.out, "0x{:02X} ", reg)?;
+        }
+
+        let flag = |f: u8| (vm.flags() & f > 0) as u8;
+        write!(self.out, "\nPC: 0x{:04X} | SP: 0x{:04X}", vm.pc(), vm.sp())?;
+        match vm.bank() {
+            Some(bank) => writeln!(self.out, " | Bank: {}", bank)?,
+            None => writeln!(self.out)?,
+        }
+        writeln!(
+            self.out,
+            "Flags: Z={} C={} N={} V={} I={}",
+            flag(FLAG_ZERO),
+            flag(FLAG_CARRY),
+            flag(FLAG_NEGATIVE),
+            flag(FLAG_OVERFLOW),
+            flag(FLAG_INTERRUPT)
+        )
+    }
+
+    // set %R VALUE | set pc ADDR | set sp VALUE
+    fn set(&mut self, vm: &mut VM, args: &[&str]) -> io::Result<()> {
+        let (target, value) = match args {
+            [target, value] => (target.to_lowercase(), *value),
+            _ => return writeln!(self.out, "Usage: set %R|pc|sp VALUE"),
+        };
+
+        match target.as_str() {
+            "pc" => match self.parse_location(value) {
+                Some(addr) => vm.set_pc(addr),
+                None => writeln!(self.out, "Invalid address `{}`", value)?,
+            },
+            "sp" => match parse_value(value) {
+                Some(sp) => vm.set_sp(sp),
+                None => writeln!(self.out, "Invalid value `{}`", value)?,
+            },
+            reg => match (parse_register(reg), parse_value(value)) {
+                (Some(r), Some(data)) => {
+                    if let Err(e) = vm.set_register(r, data) {
+                        writeln!(self.out, "{}", e)?;
+                    }
+                }
+                (None, _) => writeln!(self.out, "Invalid register `{}`", args[0])?,
+                (_, None) => writeln!(self.out, "Invalid value `{}`", value)?,
+            },
+        }
+        Ok(())
+    }
+
+    // poke ADDR VALUE...
+    fn poke(&mut self, vm: &mut VM, args: &[&str]) -> io::Result<()> {
+        let addr = match args.first().and_then(|a| self.parse_location(a)) {
+            Some(addr) if args.len() > 1 => addr,
+            _ => return writeln!(self.out, "Usage: poke ADDR VALUE..."),
+        };
+
+        let mut values = Vec::with_capacity(args.len() - 1);
+        for arg in &args[1..] {
+            match parse_value(arg).filter(|v| *v <= 0xFF) {
+                Some(v) => values.push(v as u8),
+                None => return writeln!(self.out, "Invalid byte `{}`", arg),
+            }
+        }
+
+        for (i, v) in values.into_iter().enumerate() {
+            vm.poke(addr.wrapping_add(i as u16), v);
+        }
+        Ok(())
+    }
+
+    // Disassemble the instructions around PC
+    fn list(&mut self, vm: &VM) -> io::Result<()> {
+        let pc = vm.pc();
+
+        for line in self.lines_before(vm, pc, LIST_LINES) {
+            writeln!(self.out, "   {}", line)?;
+        }
+        for (i, line) in vm.disassemble(pc, LIST_LINES + 1).iter().enumerate() {
+            let marker = if i == 0 { "=>" } else { "  " };
+            writeln!(self.out, "{} {}", marker, line)?;
+        }
+        Ok(())
+    }
+
+    // Instructions don't have a fixed size so disassembling backwards is a
+    // guess. Start from the closest label if there is one, otherwise from
+    // the earliest address that lines up with PC.
+    fn lines_before(&self, vm: &VM, pc: u16, count: usize) -> Vec<Line> {
+        let label = self
+            .symbols
+            .nearest(pc)
+            .map(|(addr, _)| addr)
+            .filter(|addr| *addr < pc && pc - *addr <= LIST_LABEL_RANGE);
+
+        let max_back = (count * 4) as u16;
+        let starts = label
+            .into_iter()
+            .chain((1..=max_back.min(pc)).rev().map(|back| pc - back));
+
+        for start in starts {
+            let mut lines: Vec<Line> = vm
+                .disassemble(start, usize::from(pc - start))
+                .into_iter()
+                .take_while(|l| l.addr < pc)
+                .collect();
+
+            if let Some(last) = lines.last()
+                && usize::from(last.addr) + last.bytes.len() == usize::from(pc)
+            {
+                let skip = lines.len().saturating_sub(count);
+                return lines.split_off(skip);
+            }
+        }
+        Vec::new()
+    }
+
+    // break ADDR [if %R OP VALUE]
+    fn add_break(&mut self, vm: &mut VM, args: &[&str]) -> io::Result<()> {
+        let addr = match args.first().and_then(|a| self.parse_location(a)) {
+            Some(addr) => addr,
+            None => return writeln!(self.out, "Usage: break ADDR [if %R OP VALUE]"),
+        };
+
+        let condition = match args.get(1) {
+            Some(&"if") => match args[2..].join(" ").parse::<Condition>() {
+                Ok(cond) => Some(cond),
+                Err(e) => return writeln!(self.out, "{}", e),
+            },
+            Some(_) => return writeln!(self.out, "Usage: break ADDR [if %R OP VALUE]"),
+            None => None,
+        };
+
+        vm.add_breakpoint(addr, condition);
+        Ok(())
+    }
+
+    // watch START [END]
+    fn add_watch(&mut self, vm: &mut VM, args: &[&str], kind: WatchKind) -> io::Result<()> {
+        let start = args.first().and_then(|a| self.parse_location(a));
+        let end = match args.get(1) {
+            Some(a) => self.parse_location(a),
+            None => start,
+        };
+
+        match (start, end) {
+            (Some(start), Some(end)) if start <= end => {
+                vm.add_watchpoint(start..=end, kind);
+                Ok(())
+            }
+            _ => writeln!(self.out, "Usage: watch START [END]"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::CodeSection;
+    use crate::devices::OutputBuffer;
+    use crate::opcodes::OpCode as opc;
+
+    // Prints %1 after pausing on a DEBUG instruction
+    fn debug_vm() -> VM {
+        let mut vm = VM::new();
+        vm.install_code(&[CodeSection {
+            org: 0,
+            bank: 0,
+            code: [
+                &[opc::LOADI as u8, 1, 0x00, b'A'][..],
+                &[opc::DEBUG as u8],
+                &[opc::STRA as u8, 1, 0xFF, 0xFD],
+                &[opc::HALT as u8],
+            ]
+            .concat(),
+        }]);
+        vm
+    }
+
+    fn session(vm: &mut VM, script: &str, input: &str) -> String {
+        let out = OutputBuffer::new();
+        let mut debugger = Debugger::with_io(
+            Box::new(io::Cursor::new(input.as_bytes().to_vec())),
+            Box::new(out.clone()),
+        );
+        debugger.set_script(Box::new(io::Cursor::new(script.as_bytes().to_vec())));
+        debugger.run(vm).unwrap();
+        out.contents()
+    }
+
+    #[test]
+    fn test_script() {
+        let mut vm = debug_vm();
+        let out = session(
+            &mut vm,
+            "set %1 0x42\npoke 0x40 0x48 105\nmem 40 41\nstep\n",
+            "con\n",
+        );
+
+        assert_eq!(
+            out,
+            "Paused at 0x0005
+=> STR %1 0xFFFD           ; 0005  1B 01 FF FD
+Debug> set %1 0x42
+Debug> poke 0x40 0x48 105
+Debug> mem 40 41
+0040  48 69                                            |Hi|
+Debug> step
+=> HALT                    ; 0009  12
+Debug> "
+        );
+        assert_eq!(vm.output(), "B");
+        assert!(vm.is_halted());
+    }
+
+    #[test]
+    fn test_back() {
+        let mut vm = debug_vm();
+        let out = session(&mut vm, "back abc\nback -1\nback\n", "con\n");
+
+        assert!(
+            out.contains("Debug> back abc\nUsage: back [N]\n"),
+            "{}",
+            out
+        );
+        assert!(out.contains("Debug> back -1\nUsage: back [N]\n"), "{}", out);
+        assert!(
+            out.contains("Debug> back\nStepped back 1 instructions"),
+            "{}",
+            out
+        );
+        assert_eq!(vm.output(), "A");
+    }
+
+    #[test]
+    fn test_end_of_input_continues() {
+        let mut vm = debug_vm();
+        vm.add_breakpoint(0x9, None);
+        session(&mut vm, "", "");
+
+        assert_eq!(vm.output(), "A");
+        assert!(vm.is_halted());
+    }
+
+    #[test]
+    fn test_parse_location() {
+        let mut debugger = Debugger::with_io(Box::new(io::empty()), Box::new(io::sink()));
+        let mut symbols = SymbolTable::new();
+        symbols.insert("loop", 0x20);
+        symbols.insert("add", 0x30);
+        debugger.set_symbols(symbols);
+
+        assert_eq!(debugger.parse_location("loop"), Some(0x20));
+        assert_eq!(debugger.parse_location("loop+10"), Some(0x2A));
+        assert_eq!(debugger.parse_location("add"), Some(0x30));
+        assert_eq!(debugger.parse_location("0x2B"), Some(0x2B));
+        assert_eq!(debugger.parse_location("002B"), Some(0x2B));
+        assert_eq!(debugger.parse_location("nope"), None);
+    }
+}
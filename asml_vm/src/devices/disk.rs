@@ -0,0 +1,217 @@
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::RangeInclusive;
+use std::path::Path;
+
+use super::{Device, Memory};
+
+pub const DISK_SECTOR: u16 = 0xFFE0;
+pub const DISK_BUFFER: u16 = 0xFFE2;
+pub const DISK_COMMAND: u16 = 0xFFE4;
+pub const DISK_STATUS: u16 = 0xFFE5;
+pub const DISK_SIZE: u16 = 0xFFE6;
+
+pub const SECTOR_SIZE: usize = 256;
+
+// Commands
+pub const DISK_READ: u8 = 1;
+pub const DISK_WRITE: u8 = 2;
+
+// Status register bits
+pub const DISK_BUSY: u8 = 0b01;
+pub const DISK_ERROR: u8 = 0b10;
+
+/// Storage a disk image can be kept in.
+pub trait DiskImage: Read + Write + Seek {}
+
+impl<T: Read + Write + Seek> DiskImage for T {}
+
+/// Block storage controller. The sector and buffer registers hold the sector
+/// number and the memory address of a 256 byte buffer. Writing a command
+/// starts a transfer between the sector and the buffer, which is copied
+/// directly to or from memory before the next instruction. The busy bit of
+/// the status register is set until the transfer is done, and the error bit
+/// is set when a command fails, for example when the sector is past the end
+/// of the image. The size register holds the number of sectors in the image.
+/// If the image isn't a whole number of sectors, the rest of the last sector
+/// reads as zeros.
+pub struct Disk {
+    image: Box<dyn DiskImage>,
+    sectors: u16,
+    sector: u16,
+    buffer: u16,
+    command: u8,
+    status: u8,
+}
+
+impl Disk {
+    pub fn new(mut image: Box<dyn DiskImage>) -> io::Result<Self> {
+        let len = image.seek(SeekFrom::End(0))?;
+        let sectors = len.div_ceil(SECTOR_SIZE as u64).min(u64::from(u16::MAX)) as u16;
+
+        Ok(Disk {
+            image,
+            sectors,
+            sector: 0,
+            buffer: 0,
+            command: 0,
+            status: 0,
+        })
+    }
+
+    /// Open an image file for reading and writing.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        Disk::new(Box::new(file))
+    }
+
+    pub fn sectors(&self) -> u16 {
+        self.sectors
+    }
+
+    fn transfer(&mut self, memory: &mut Memory) -> io::Result<()> {
+        if self.sector >= self.sectors {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "sector past the end of the image",
+            ));
+        }
+        self.image
+            .seek(SeekFrom::Start(u64::from(self.sector) * SECTOR_SIZE as u64))?;
+
+        match self.command {
+            DISK_READ => {
+                let mut data = Vec::with_capacity(SECTOR_SIZE);
+                (&mut self.image)
+                    .take(SECTOR_SIZE as u64)
+                    .read_to_end(&mut data)?;
+                data.resize(SECTOR_SIZE, 0);
+
+                for (i, b) in data.into_iter().enumerate() {
+                    memory.poke(self.buffer.wrapping_add(i as u16), b);
+                }
+            }
+            DISK_WRITE => {
+                let data: Vec<u8> = (0..SECTOR_SIZE)
+                    .map(|i| memory.peek(self.buffer.wrapping_add(i as u16)))
+                    .collect();
+                self.image.write_all(&data)?;
+                self.image.flush()?;
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "unknown command",
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Device for Disk {
+    fn range(&self) -> RangeInclusive<u16> {
+        DISK_SECTOR..=DISK_SIZE + 1
+    }
+
+    fn on_read(&mut self, addr: u16) -> u8 {
+        match addr {
+            DISK_SECTOR => (self.sector >> 8) as u8,
+            a if a == DISK_SECTOR + 1 => self.sector as u8,
+            DISK_BUFFER => (self.buffer >> 8) as u8,
+            a if a == DISK_BUFFER + 1 => self.buffer as u8,
+            DISK_COMMAND => self.command,
+            DISK_STATUS => self.status,
+            DISK_SIZE => (self.sectors >> 8) as u8,
+            _ => self.sectors as u8,
+        }
+    }
+
+    fn on_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            DISK_SECTOR => self.sector = (self.sector & 0x00FF) | (u16::from(data) << 8),
+            a if a == DISK_SECTOR + 1 => self.sector = (self.sector & 0xFF00) | u16::from(data),
+            DISK_BUFFER => self.buffer = (self.buffer & 0x00FF) | (u16::from(data) << 8),
+            a if a == DISK_BUFFER + 1 => self.buffer = (self.buffer & 0xFF00) | u16::from(data),
+            DISK_COMMAND => {
+                self.command = data;
+                self.status = DISK_BUSY;
+            }
+            _ => {}
+        }
+    }
+
+    fn dma(&mut self, memory: &mut Memory) {
+        if self.status & DISK_BUSY == 0 {
+            return;
+        }
+
+        // The program only sees the error bit, there's nowhere to report why
+        self.status = match self.transfer(memory) {
+            Ok(()) => 0,
+            Err(_) => DISK_ERROR,
+        };
+    }
+}
+
+impl fmt::Debug for Disk {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Disk")
+            .field("sectors", &self.sectors)
+            .field("sector", &self.sector)
+            .field("buffer", &self.buffer)
+            .field("command", &self.command)
+            .field("status", &self.status)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::devices::Bus;
+
+    #[test]
+    fn test_disk() {
+        use std::io::Cursor;
+
+        // Two and a bit sectors
+        let mut image: Vec<u8> = (0..2 * SECTOR_SIZE)
+            .map(|i| (i / SECTOR_SIZE) as u8 + 1)
+            .collect();
+        image.extend_from_slice(&[0xAA; 10]);
+
+        let mut bus = Bus::new(0x10000);
+        bus.attach(Box::new(Disk::new(Box::new(Cursor::new(image))).unwrap()));
+        assert_eq!(bus.read(DISK_SIZE + 1), 3);
+
+        let command = |bus: &mut Bus, sector: u16, buffer: u16, command: u8| {
+            bus.write(DISK_SECTOR + 1, sector as u8);
+            bus.write(DISK_BUFFER, (buffer >> 8) as u8);
+            bus.write(DISK_BUFFER + 1, buffer as u8);
+            bus.write(DISK_COMMAND, command);
+            assert_eq!(bus.read(DISK_STATUS), DISK_BUSY);
+            bus.tick();
+            bus.read(DISK_STATUS)
+        };
+
+        assert_eq!(command(&mut bus, 1, 0x1000, DISK_READ), 0);
+        assert_eq!(bus.peek(0x1000), 2);
+        assert_eq!(bus.peek(0x10FF), 2);
+        assert_eq!(bus.peek(0x1100), 0);
+
+        // The end of a partial sector reads as zeros
+        assert_eq!(command(&mut bus, 2, 0x2000, DISK_READ), 0);
+        assert_eq!(bus.peek(0x2009), 0xAA);
+        assert_eq!(bus.peek(0x200A), 0);
+
+        // Write sector 1 to sector 0 and read it back
+        assert_eq!(command(&mut bus, 0, 0x1000, DISK_WRITE), 0);
+        assert_eq!(command(&mut bus, 0, 0x3000, DISK_READ), 0);
+        assert_eq!(bus.peek(0x3000), 2);
+
+        assert_eq!(command(&mut bus, 3, 0x3000, DISK_READ), DISK_ERROR);
+        assert_eq!(command(&mut bus, 0, 0x3000, 0x42), DISK_ERROR);
+    }
+}
@@ -0,0 +1,251 @@
+use std::fmt;
+use std::io::{self, Write};
+use std::ops::RangeInclusive;
+use std::time::{Duration, Instant};
+
+use super::Device;
+
+pub const DISPLAY_WIDTH: usize = 40;
+pub const DISPLAY_HEIGHT: usize = 25;
+pub const DISPLAY_MEMORY: u16 = 0xF000;
+pub const DISPLAY_MEMORY_END: u16 = DISPLAY_MEMORY + (DISPLAY_WIDTH * DISPLAY_HEIGHT) as u16 - 1;
+pub const DISPLAY_CURSOR_X: u16 = 0xFFC8;
+pub const DISPLAY_CURSOR_Y: u16 = 0xFFC9;
+pub const DISPLAY_CONTROL: u16 = 0xFFCA;
+
+// Control register bits
+pub const DISPLAY_CLEAR: u8 = 0b01;
+pub const DISPLAY_SHOW_CURSOR: u8 = 0b10;
+
+// Frames drawn on a terminal are limited to about 30 a second
+const FRAME_TIME: Duration = Duration::from_millis(33);
+
+/// 40x25 character display. Each byte of display memory is the ASCII
+/// character of one cell, row by row from the top left. Bytes outside the
+/// printable range are shown as spaces. The cursor registers hold the column
+/// and row of the cursor, which is only shown while the show cursor bit of
+/// the control register is set. The cursor is only drawn, moving it has no
+/// effect on display memory. Writing the clear bit blanks the screen and
+/// moves the cursor to the top left.
+///
+/// Without a terminal the display only keeps the cells, see `text`. With a
+/// terminal the screen is drawn at the top of it with ANSI escapes while the
+/// program runs, and other output continues below it.
+pub struct Display {
+    cells: Vec<u8>,
+    cursor: (u8, u8),
+    control: u8,
+    terminal: Option<Box<dyn Write>>,
+    dirty: bool,
+    drawn: bool,
+    last_frame: Option<Instant>,
+}
+
+impl Default for Display {
+    fn default() -> Self {
+        Display::new()
+    }
+}
+
+impl Display {
+    pub fn new() -> Self {
+        Display {
+            cells: vec![b' '; DISPLAY_WIDTH * DISPLAY_HEIGHT],
+            cursor: (0, 0),
+            control: 0,
+            terminal: None,
+            dirty: false,
+            drawn: false,
+            last_frame: None,
+        }
+    }
+
+    /// Create a display drawn on an ANSI terminal.
+    pub fn with_terminal(out: Box<dyn Write>) -> Self {
+        Display {
+            terminal: Some(out),
+            ..Display::new()
+        }
+    }
+
+    /// The character in a cell.
+    pub fn cell(&self, col: usize, row: usize) -> u8 {
+        self.cells[row * DISPLAY_WIDTH + col]
+    }
+
+    pub fn cursor(&self) -> (u8, u8) {
+        self.cursor
+    }
+
+    /// The screen as plain text, one line per row with trailing spaces
+    /// and blank rows at the bottom removed.
+    pub fn text(&self) -> String {
+        let rows: Vec<String> = self
+            .cells
+            .chunks(DISPLAY_WIDTH)
+            .map(|row| {
+                let line: String = row.iter().map(|c| printable(*c)).collect();
+                line.trim_end().to_owned()
+            })
+            .collect();
+
+        let used = rows
+            .iter()
+            .rposition(|r| !r.is_empty())
+            .map_or(0, |i| i + 1);
+        rows[..used].iter().map(|r| format!("{}\n", r)).collect()
+    }
+
+    /// Draw the screen on the terminal if it changed since the last frame.
+    pub fn render(&mut self) -> io::Result<()> {
+        if !self.dirty && self.drawn {
+            return Ok(());
+        }
+        let out = match &mut self.terminal {
+            Some(out) => out,
+            None => return Ok(()),
+        };
+
+        // The terminal's own cursor stays with other output, the display's
+        // cursor is drawn in reverse video
+        let cursor = match self.control & DISPLAY_SHOW_CURSOR {
+            0 => None,
+            _ => Some(usize::from(self.cursor.1) * DISPLAY_WIDTH + usize::from(self.cursor.0)),
+        };
+
+        let mut frame = String::new();
+        if self.drawn {
+            frame.push_str("\x1b7");
+        } else {
+            frame.push_str("\x1b[2J");
+        }
+        frame.push_str("\x1b[H");
+
+        for (i, c) in self.cells.iter().enumerate() {
+            if Some(i) == cursor {
+                frame.push_str(&format!("\x1b[7m{}\x1b[27m", printable(*c)));
+            } else {
+                frame.push(printable(*c));
+            }
+            if (i + 1) % DISPLAY_WIDTH == 0 {
+                frame.push_str("\x1b[K\r\n");
+            }
+        }
+
+        if self.drawn {
+            frame.push_str("\x1b8");
+        } else {
+            // Other output goes below the screen
+            frame.push_str(&format!("\x1b[{};1H", DISPLAY_HEIGHT + 2));
+        }
+
+        out.write_all(frame.as_bytes())?;
+        out.flush()?;
+
+        self.dirty = false;
+        self.drawn = true;
+        self.last_frame = Some(Instant::now());
+        Ok(())
+    }
+
+    fn clear(&mut self) {
+        self.cells.iter_mut().for_each(|c| *c = b' ');
+        self.cursor = (0, 0);
+    }
+}
+
+fn printable(c: u8) -> char {
+    if c.is_ascii_graphic() { c as char } else { ' ' }
+}
+
+impl Device for Display {
+    fn range(&self) -> RangeInclusive<u16> {
+        DISPLAY_MEMORY..=DISPLAY_CONTROL
+    }
+
+    fn contains(&self, addr: u16) -> bool {
+        (DISPLAY_MEMORY..=DISPLAY_MEMORY_END).contains(&addr)
+            || (DISPLAY_CURSOR_X..=DISPLAY_CONTROL).contains(&addr)
+    }
+
+    fn on_read(&mut self, addr: u16) -> u8 {
+        match addr {
+            DISPLAY_CURSOR_X => self.cursor.0,
+            DISPLAY_CURSOR_Y => self.cursor.1,
+            DISPLAY_CONTROL => self.control & DISPLAY_SHOW_CURSOR,
+            _ => self.cells[usize::from(addr - DISPLAY_MEMORY)],
+        }
+    }
+
+    fn on_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            DISPLAY_CURSOR_X => self.cursor.0 = data,
+            DISPLAY_CURSOR_Y => self.cursor.1 = data,
+            DISPLAY_CONTROL => {
+                if data & DISPLAY_CLEAR > 0 {
+                    self.clear();
+                }
+                self.control = data & DISPLAY_SHOW_CURSOR;
+            }
+            _ => self.cells[usize::from(addr - DISPLAY_MEMORY)] = data,
+        }
+        self.dirty = true;
+    }
+
+    fn tick(&mut self) {
+        if self.dirty
+            && self.terminal.is_some()
+            && self.last_frame.is_none_or(|t| t.elapsed() >= FRAME_TIME)
+        {
+            // Like the printer, a program can't do anything about a broken
+            // terminal
+            let _ = self.render();
+        }
+    }
+}
+
+impl fmt::Debug for Display {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Display")
+            .field("cursor", &self.cursor)
+            .field("control", &self.control)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::devices::Bus;
+
+    #[test]
+    fn test_display() {
+        let mut bus = Bus::new(0x10000);
+        bus.attach(Box::new(Display::new()));
+
+        for (i, c) in b"Hi".iter().enumerate() {
+            bus.write(DISPLAY_MEMORY + i as u16, *c);
+        }
+        bus.write(DISPLAY_MEMORY + 2 * DISPLAY_WIDTH as u16 + 39, b'!');
+        bus.write(DISPLAY_CURSOR_X, 5);
+        bus.write(DISPLAY_CONTROL, DISPLAY_SHOW_CURSOR);
+
+        assert_eq!(bus.read(DISPLAY_MEMORY + 1), b'i');
+        assert_eq!(bus.read(DISPLAY_CURSOR_X), 5);
+        assert_eq!(bus.read(DISPLAY_CONTROL), DISPLAY_SHOW_CURSOR);
+
+        // Memory between the display memory and the registers is normal RAM
+        assert!(!bus.write(DISPLAY_MEMORY_END + 1, 0x42));
+        assert_eq!(bus.peek(DISPLAY_MEMORY_END + 1), 0x42);
+
+        let mut display = Display::new();
+        display.on_write(DISPLAY_MEMORY, b'H');
+        display.on_write(DISPLAY_MEMORY + 81, 0x07);
+        display.on_write(DISPLAY_MEMORY + 82, b'x');
+        assert_eq!(display.text(), "H\n\n  x\n");
+
+        display.on_write(DISPLAY_CONTROL, DISPLAY_CLEAR);
+        assert_eq!(display.text(), "");
+        assert_eq!(display.cursor(), (0, 0));
+    }
+}
@@ -0,0 +1,89 @@
+use std::io::{self, Read};
+use std::ops::RangeInclusive;
+
+use super::Device;
+
+pub const KEYBOARD_DATA: u16 = 0xFFF8;
+pub const KEYBOARD_STATUS: u16 = 0xFFF9;
+
+// Status register bits
+pub const KEYBOARD_READY: u8 = 0b01;
+pub const KEYBOARD_EOF: u8 = 0b10;
+
+/// Keyboard input device. Reading the data register returns the next input
+/// byte, or zero if there isn't one. The status register reports if a byte
+/// is ready or the input has ended. When reading from a terminal, checking
+/// the status waits for the next line of input.
+pub struct Keyboard {
+    input: Box<dyn Read>,
+    next: Option<u8>,
+    eof: bool,
+}
+
+impl Keyboard {
+    pub fn new(input: Box<dyn Read>) -> Self {
+        Keyboard {
+            input,
+            next: None,
+            eof: false,
+        }
+    }
+
+    pub fn stdin() -> Self {
+        Self::new(Box::new(io::stdin()))
+    }
+
+    pub fn from_bytes(data: Vec<u8>) -> Self {
+        Self::new(Box::new(io::Cursor::new(data)))
+    }
+
+    fn fill(&mut self) {
+        if self.next.is_some() || self.eof {
+            return;
+        }
+
+        let mut buf = [0; 1];
+        match self.input.read(&mut buf) {
+            Ok(1) => self.next = Some(buf[0]),
+            _ => self.eof = true,
+        }
+    }
+}
+
+impl Device for Keyboard {
+    fn range(&self) -> RangeInclusive<u16> {
+        KEYBOARD_DATA..=KEYBOARD_STATUS
+    }
+
+    fn on_read(&mut self, addr: u16) -> u8 {
+        self.fill();
+
+        if addr == KEYBOARD_DATA {
+            self.next.take().unwrap_or(0)
+        } else if self.next.is_some() {
+            KEYBOARD_READY
+        } else if self.eof {
+            KEYBOARD_EOF
+        } else {
+            0
+        }
+    }
+
+    fn on_write(&mut self, _addr: u16, _data: u8) {}
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_keyboard() {
+        let mut kbd = Keyboard::from_bytes(b"ok".to_vec());
+
+        assert_eq!(kbd.on_read(KEYBOARD_STATUS), KEYBOARD_READY);
+        assert_eq!(kbd.on_read(KEYBOARD_DATA), b'o');
+        assert_eq!(kbd.on_read(KEYBOARD_DATA), b'k');
+        assert_eq!(kbd.on_read(KEYBOARD_STATUS), KEYBOARD_EOF);
+        assert_eq!(kbd.on_read(KEYBOARD_DATA), 0);
+    }
+}
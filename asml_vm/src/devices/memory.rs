@@ -0,0 +1,76 @@
+use super::mmu::{self, BANK_WINDOW, Mmu};
+
+/// Main memory, without any devices. With an MMU, accesses to the bank
+/// window go to the selected bank.
+pub struct Memory {
+    ram: Vec<u8>,
+    mmu: Option<Mmu>,
+}
+
+impl Memory {
+    pub fn new(size: usize) -> Self {
+        Memory {
+            ram: vec![0; size],
+            mmu: None,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.ram.len()
+    }
+
+    /// Add an MMU with `count` banks, or grow the existing one. The current
+    /// contents of the window become bank 0.
+    pub fn enable_banking(&mut self, count: usize) {
+        match &mut self.mmu {
+            Some(mmu) => mmu.grow(count),
+            None => {
+                let window =
+                    &self.ram[usize::from(*BANK_WINDOW.start())..=usize::from(*BANK_WINDOW.end())];
+                self.mmu = Some(Mmu::new(count, window));
+            }
+        }
+    }
+
+    pub fn mmu(&self) -> Option<&Mmu> {
+        self.mmu.as_ref()
+    }
+
+    pub fn mmu_mut(&mut self) -> Option<&mut Mmu> {
+        self.mmu.as_mut()
+    }
+
+    /// Write to a specific bank without selecting it. Addresses outside the
+    /// bank window, or any address without an MMU, write main memory.
+    pub fn poke_bank(&mut self, bank: u8, addr: u16, data: u8) {
+        match self.banked_mut(addr).and_then(|mmu| mmu.bank_mut(bank)) {
+            Some(bank) => bank[mmu::offset(addr)] = data,
+            None => self.poke(addr, data),
+        }
+    }
+
+    // The MMU if addr is in the bank window
+    fn banked(&self, addr: u16) -> Option<&Mmu> {
+        self.mmu.as_ref().filter(|_| BANK_WINDOW.contains(&addr))
+    }
+
+    fn banked_mut(&mut self, addr: u16) -> Option<&mut Mmu> {
+        self.mmu.as_mut().filter(|_| BANK_WINDOW.contains(&addr))
+    }
+
+    /// The bank window reads from the selected bank.
+    pub fn peek(&self, addr: u16) -> u8 {
+        match self.banked(addr) {
+            Some(mmu) => mmu.read(addr),
+            None => self.ram[addr as usize],
+        }
+    }
+
+    /// The bank window writes to the selected bank.
+    pub fn poke(&mut self, addr: u16, data: u8) {
+        match self.banked_mut(addr) {
+            Some(mmu) => mmu.write(addr, data),
+            None => self.ram[addr as usize] = data,
+        }
+    }
+}
@@ -0,0 +1,68 @@
+use std::ops::RangeInclusive;
+
+pub const BANK_SELECT: u16 = 0xFFFA;
+pub const BANK_WINDOW: RangeInclusive<u16> = 0x8000..=0xBFFF;
+pub const BANK_SIZE: usize = 0x4000;
+pub const MAX_BANKS: usize = 256;
+
+/// Memory management unit. Swaps the 16K window at 0x8000 - 0xBFFF between
+/// physical banks. Writing to the bank select register selects the bank seen
+/// in the window, reading it returns the selected bank. Bank numbers wrap
+/// around the number of banks. Bank 0 is selected at startup.
+#[derive(Debug, Clone)]
+pub struct Mmu {
+    banks: Vec<Vec<u8>>,
+    selected: u8,
+}
+
+impl Mmu {
+    /// Create an MMU with `count` banks. `window` is copied to bank 0.
+    pub fn new(count: usize, window: &[u8]) -> Self {
+        let mut banks = vec![vec![0; BANK_SIZE]; count.clamp(1, MAX_BANKS)];
+        banks[0].copy_from_slice(window);
+        Mmu { banks, selected: 0 }
+    }
+
+    pub fn count(&self) -> usize {
+        self.banks.len()
+    }
+
+    pub fn selected(&self) -> u8 {
+        self.selected
+    }
+
+    pub fn select(&mut self, bank: u8) {
+        self.selected = (usize::from(bank) % self.banks.len()) as u8;
+    }
+
+    /// Add banks up to `count`. Existing banks are kept.
+    pub fn grow(&mut self, count: usize) {
+        let count = count.min(MAX_BANKS);
+        if count > self.banks.len() {
+            self.banks.resize(count, vec![0; BANK_SIZE]);
+        }
+    }
+
+    /// The contents of a bank, indexed by offset from the start of the
+    /// window.
+    pub fn bank(&self, bank: u8) -> Option<&[u8]> {
+        self.banks.get(usize::from(bank)).map(|b| &b[..])
+    }
+
+    pub fn bank_mut(&mut self, bank: u8) -> Option<&mut [u8]> {
+        self.banks.get_mut(usize::from(bank)).map(|b| &mut b[..])
+    }
+
+    pub(crate) fn read(&self, addr: u16) -> u8 {
+        self.banks[usize::from(self.selected)][offset(addr)]
+    }
+
+    pub(crate) fn write(&mut self, addr: u16, data: u8) {
+        self.banks[usize::from(self.selected)][offset(addr)] = data;
+    }
+}
+
+// Offset of a window address in a bank
+pub(crate) fn offset(addr: u16) -> usize {
+    usize::from(addr - BANK_WINDOW.start())
+}
@@ -0,0 +1,278 @@
+mod disk;
+mod display;
+mod keyboard;
+mod memory;
+mod mmu;
+mod printer;
+#[cfg(target_os = "linux")]
+mod pty;
+mod timer;
+mod uart;
+
+pub use self::disk::{
+    DISK_BUFFER, DISK_BUSY, DISK_COMMAND, DISK_ERROR, DISK_READ, DISK_SECTOR, DISK_SIZE,
+    DISK_STATUS, DISK_WRITE, Disk, DiskImage, SECTOR_SIZE,
+};
+pub use self::display::{
+    DISPLAY_CLEAR, DISPLAY_CONTROL, DISPLAY_CURSOR_X, DISPLAY_CURSOR_Y, DISPLAY_HEIGHT,
+    DISPLAY_MEMORY, DISPLAY_MEMORY_END, DISPLAY_SHOW_CURSOR, DISPLAY_WIDTH, Display,
+};
+pub use self::keyboard::{KEYBOARD_DATA, KEYBOARD_EOF, KEYBOARD_READY, KEYBOARD_STATUS, Keyboard};
+pub use self::memory::Memory;
+pub use self::mmu::{BANK_SELECT, BANK_SIZE, BANK_WINDOW, MAX_BANKS, Mmu};
+pub use self::printer::{OutputBuffer, PRINTER_ADDR, Printer};
+pub use self::timer::{
+    TIMER_CONTROL, TIMER_COUNTER, TIMER_ENABLE, TIMER_EXPIRED, TIMER_IRQ_ENABLE, TIMER_RELOAD,
+    TIMER_STATUS, Timer,
+};
+pub use self::uart::{
+    UART_CONNECTED, UART_CONTROL, UART_DATA, UART_RX_IRQ_ENABLE, UART_RX_READY, UART_STATUS, Uart,
+};
+
+use std::cell::RefCell;
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::rc::Rc;
+
+/// A memory mapped device. Reads and writes to an address in the device's
+/// range are sent to the device instead of memory.
+pub trait Device {
+    /// Addresses handled by the device
+    fn range(&self) -> RangeInclusive<u16>;
+
+    /// Whether the device handles `addr`. Devices that only use part of
+    /// their range, leaving the rest to memory, override this.
+    fn contains(&self, addr: u16) -> bool {
+        self.range().contains(&addr)
+    }
+
+    fn on_read(&mut self, addr: u16) -> u8;
+
+    fn on_write(&mut self, addr: u16, data: u8);
+
+    /// Called after every executed instruction
+    fn tick(&mut self) {}
+
+    /// Called after `tick` with direct access to memory, for devices that
+    /// copy data to or from memory without the CPU. Transfers bypass other
+    /// devices and memory protection.
+    fn dma(&mut self, _memory: &mut Memory) {}
+
+    /// The state of the device's interrupt line. The line should stay raised
+    /// until the program acknowledges the interrupt.
+    fn interrupt(&self) -> bool {
+        false
+    }
+}
+
+// Allows the owner of a device to keep a handle to it after it's attached
+impl<D: Device> Device for Rc<RefCell<D>> {
+    fn range(&self) -> RangeInclusive<u16> {
+        self.borrow().range()
+    }
+
+    fn contains(&self, addr: u16) -> bool {
+        self.borrow().contains(addr)
+    }
+
+    fn on_read(&mut self, addr: u16) -> u8 {
+        self.borrow_mut().on_read(addr)
+    }
+
+    fn on_write(&mut self, addr: u16, data: u8) {
+        self.borrow_mut().on_write(addr, data)
+    }
+
+    fn tick(&mut self) {
+        self.borrow_mut().tick()
+    }
+
+    fn dma(&mut self, memory: &mut Memory) {
+        self.borrow_mut().dma(memory)
+    }
+
+    fn interrupt(&self) -> bool {
+        self.borrow().interrupt()
+    }
+}
+
+/// The memory bus routes memory accesses to either main memory or a device.
+pub struct Bus {
+    memory: Memory,
+    devices: Vec<Box<dyn Device>>,
+}
+
+impl Bus {
+    pub fn new(size: usize) -> Self {
+        Bus {
+            memory: Memory::new(size),
+            devices: Vec::new(),
+        }
+    }
+
+    /// Add an MMU with `count` banks, or grow the existing one. The current
+    /// contents of the window become bank 0.
+    pub fn enable_banking(&mut self, count: usize) {
+        self.memory.enable_banking(count);
+    }
+
+    pub fn mmu(&self) -> Option<&Mmu> {
+        self.memory.mmu()
+    }
+
+    pub fn mmu_mut(&mut self) -> Option<&mut Mmu> {
+        self.memory.mmu_mut()
+    }
+
+    /// Write to a specific bank without selecting it. Addresses outside the
+    /// bank window, or any address without an MMU, write main memory.
+    pub fn poke_bank(&mut self, bank: u8, addr: u16, data: u8) {
+        self.memory.poke_bank(bank, addr, data);
+    }
+
+    /// Attach a device to the bus. When device ranges overlap, the device
+    /// attached last is used.
+    pub fn attach(&mut self, device: Box<dyn Device>) {
+        self.devices.push(device);
+    }
+
+    fn device(&mut self, addr: u16) -> Option<&mut Box<dyn Device>> {
+        self.devices.iter_mut().rev().find(|d| d.contains(addr))
+    }
+
+    pub fn read(&mut self, addr: u16) -> u8 {
+        if addr == BANK_SELECT
+            && let Some(mmu) = self.memory.mmu()
+        {
+            return mmu.selected();
+        }
+
+        match self.device(addr) {
+            Some(dev) => dev.on_read(addr),
+            None => self.peek(addr),
+        }
+    }
+
+    /// Write a byte to the bus, returns true if the write went to a device.
+    pub fn write(&mut self, addr: u16, data: u8) -> bool {
+        if addr == BANK_SELECT
+            && let Some(mmu) = self.memory.mmu_mut()
+        {
+            mmu.select(data);
+            return true;
+        }
+
+        match self.device(addr) {
+            Some(dev) => {
+                dev.on_write(addr, data);
+                true
+            }
+            None => {
+                self.poke(addr, data);
+                false
+            }
+        }
+    }
+
+    /// Read main memory without going through any devices. The bank window
+    /// reads from the selected bank.
+    pub fn peek(&self, addr: u16) -> u8 {
+        self.memory.peek(addr)
+    }
+
+    /// Write main memory without going through any devices. The bank window
+    /// writes to the selected bank.
+    pub fn poke(&mut self, addr: u16, data: u8) {
+        self.memory.poke(addr, data);
+    }
+
+    pub fn tick(&mut self) {
+        for dev in &mut self.devices {
+            dev.tick();
+            dev.dma(&mut self.memory);
+        }
+    }
+
+    /// Returns true if any device has raised its interrupt line.
+    pub fn interrupt_pending(&self) -> bool {
+        self.devices.iter().any(|d| d.interrupt())
+    }
+}
+
+impl fmt::Debug for Bus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Bus")
+            .field("memory", &self.memory.size())
+            .field("devices", &self.devices.len())
+            .field("banks", &self.memory.mmu().map(|m| m.count()))
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Default)]
+    struct Latch {
+        value: u8,
+        ticks: u32,
+    }
+
+    impl Device for Latch {
+        fn range(&self) -> RangeInclusive<u16> {
+            0x1000..=0x1001
+        }
+
+        fn on_read(&mut self, addr: u16) -> u8 {
+            self.value.wrapping_add((addr - 0x1000) as u8)
+        }
+
+        fn on_write(&mut self, _addr: u16, data: u8) {
+            self.value = data;
+        }
+
+        fn tick(&mut self) {
+            self.ticks += 1;
+        }
+    }
+
+    #[test]
+    fn test_bus_routes_to_device() {
+        let latch = Rc::new(RefCell::new(Latch::default()));
+        let mut bus = Bus::new(0x10000);
+        bus.attach(Box::new(latch.clone()));
+
+        assert!(bus.write(0x1000, 0x42));
+        assert!(!bus.write(0x1002, 0x24));
+        assert_eq!(bus.read(0x1000), 0x42);
+        assert_eq!(bus.read(0x1001), 0x43);
+        assert_eq!(bus.read(0x1002), 0x24);
+        assert_eq!(bus.peek(0x1000), 0);
+
+        bus.tick();
+        assert_eq!(latch.borrow().ticks, 1);
+    }
+
+    #[test]
+    fn test_bank_switching() {
+        let mut bus = Bus::new(0x10000);
+        bus.poke(0x8000, 0x11);
+        bus.enable_banking(3);
+
+        assert_eq!(bus.read(BANK_SELECT), 0);
+        assert_eq!(bus.read(0x8000), 0x11);
+
+        assert!(bus.write(BANK_SELECT, 2));
+        assert_eq!(bus.read(0x8000), 0);
+        bus.write(0x8000, 0x22);
+        bus.write(0xC000, 0x33);
+        assert_eq!(bus.mmu().unwrap().bank(2).unwrap()[0], 0x22);
+
+        // Bank numbers wrap around
+        bus.write(BANK_SELECT, 3);
+        assert_eq!(bus.read(BANK_SELECT), 0);
+        assert_eq!(bus.read(0x8000), 0x11);
+        assert_eq!(bus.read(0xC000), 0x33);
+    }
+}
@@ -0,0 +1,149 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::io::{self, Write};
+use std::ops::RangeInclusive;
+use std::rc::Rc;
+
+use super::Device;
+
+pub const PRINTER_ADDR: u16 = 0xFFFD;
+
+/// In-memory printer output. Clones share the same buffer so a handle can be
+/// kept after giving the buffer to a printer.
+#[derive(Debug, Clone, Default)]
+pub struct OutputBuffer(Rc<RefCell<Vec<u8>>>);
+
+impl OutputBuffer {
+    pub fn new() -> Self {
+        OutputBuffer::default()
+    }
+
+    pub fn contents(&self) -> String {
+        String::from_utf8_lossy(&self.0.borrow()).into_owned()
+    }
+
+    pub fn clear(&self) {
+        self.0.borrow_mut().clear();
+    }
+}
+
+impl Write for OutputBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Character printer. Writing a non-zero byte to the printer address prints
+/// the byte's ASCII representation. The address always reads as zero.
+///
+/// Characters are written to the printer's sink as they're printed, encoded
+/// as UTF-8. By default the sink is an `OutputBuffer`.
+pub struct Printer {
+    sink: Box<dyn Write>,
+    // Set while the sink is the default in-memory buffer
+    buffer: Option<OutputBuffer>,
+}
+
+impl Default for Printer {
+    fn default() -> Self {
+        Printer::new()
+    }
+}
+
+impl Printer {
+    pub fn new() -> Self {
+        let buffer = OutputBuffer::new();
+        Printer {
+            sink: Box::new(buffer.clone()),
+            buffer: Some(buffer),
+        }
+    }
+
+    /// Create a printer that writes to `sink` instead of memory.
+    pub fn with_sink(sink: Box<dyn Write>) -> Self {
+        Printer { sink, buffer: None }
+    }
+
+    pub fn set_sink(&mut self, sink: Box<dyn Write>) {
+        self.sink = sink;
+        self.buffer = None;
+    }
+
+    /// Output collected in memory. Empty when printing to another sink.
+    pub fn output(&self) -> String {
+        match &self.buffer {
+            Some(buffer) => buffer.contents(),
+            None => String::new(),
+        }
+    }
+
+    // Restores the in-memory output from a snapshot. Other sinks have
+    // already shown the output so it isn't written again.
+    pub(crate) fn restore_output(&mut self, output: &str) {
+        if let Some(buffer) = &self.buffer {
+            buffer.clear();
+            buffer.0.borrow_mut().extend_from_slice(output.as_bytes());
+        }
+    }
+}
+
+impl Device for Printer {
+    fn range(&self) -> RangeInclusive<u16> {
+        PRINTER_ADDR..=PRINTER_ADDR
+    }
+
+    fn on_read(&mut self, _addr: u16) -> u8 {
+        0
+    }
+
+    fn on_write(&mut self, _addr: u16, data: u8) {
+        if data > 0 {
+            let mut buf = [0; 4];
+            let encoded = (data as char).encode_utf8(&mut buf);
+
+            // A program has no way to handle a failing printer so errors
+            // are dropped, the same as a printer with no paper.
+            let _ = self
+                .sink
+                .write_all(encoded.as_bytes())
+                .and_then(|_| self.sink.flush());
+        }
+    }
+}
+
+impl fmt::Debug for Printer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Printer")
+            .field("buffer", &self.buffer)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_printer() {
+        let mut printer = Printer::new();
+        printer.on_write(PRINTER_ADDR, b'H');
+        printer.on_write(PRINTER_ADDR, 0);
+        printer.on_write(PRINTER_ADDR, b'i');
+
+        assert_eq!(printer.output(), "Hi");
+        assert_eq!(printer.on_read(PRINTER_ADDR), 0);
+
+        let sink = OutputBuffer::new();
+        printer.set_sink(Box::new(sink.clone()));
+        printer.on_write(PRINTER_ADDR, b'!');
+        printer.on_write(PRINTER_ADDR, 0xE9);
+
+        assert_eq!(sink.contents(), "!\u{e9}");
+        assert_eq!(printer.output(), "");
+    }
+}
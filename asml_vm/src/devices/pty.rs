@@ -0,0 +1,78 @@
+// Pseudo-terminals for the serial port. std doesn't wrap them and the crate
+// has no dependencies, so the few libc functions needed are declared here.
+// The constants are glibc and musl's on Linux, the only target this is built
+// for.
+
+use std::ffi::CStr;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::os::raw::{c_char, c_int, c_void};
+use std::path::PathBuf;
+
+const O_RDWR: c_int = 2;
+const TCSANOW: c_int = 0;
+
+unsafe extern "C" {
+    fn posix_openpt(flags: c_int) -> c_int;
+    fn grantpt(fd: c_int) -> c_int;
+    fn unlockpt(fd: c_int) -> c_int;
+    fn ptsname(fd: c_int) -> *mut c_char;
+    fn tcgetattr(fd: c_int, termios: *mut c_void) -> c_int;
+    fn tcsetattr(fd: c_int, action: c_int, termios: *const c_void) -> c_int;
+    fn cfmakeraw(termios: *mut c_void);
+}
+
+// Large enough for struct termios on any platform, its fields are only
+// touched by libc
+#[repr(C, align(8))]
+struct Termios([u8; 256]);
+
+pub(crate) struct Pty {
+    pub master: File,
+    // Held open so the master doesn't see a hangup each time a client
+    // closes the terminal
+    pub slave: File,
+    pub path: PathBuf,
+}
+
+fn check(ret: c_int) -> io::Result<c_int> {
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+/// Open a new pseudo-terminal in raw mode, so bytes pass through unchanged
+/// and aren't echoed.
+pub(crate) fn open() -> io::Result<Pty> {
+    // SAFETY: the descriptor is owned by the returned File, ptsname's result
+    // is copied before any other call, and termios is larger than libc's
+    // struct
+    unsafe {
+        let fd = check(posix_openpt(O_RDWR))?;
+        let master = File::from_raw_fd(fd);
+        check(grantpt(fd))?;
+        check(unlockpt(fd))?;
+
+        let name = ptsname(fd);
+        if name.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        let path = PathBuf::from(CStr::from_ptr(name).to_string_lossy().into_owned());
+
+        let slave = OpenOptions::new().read(true).write(true).open(&path)?;
+        let mut termios = Termios([0; 256]);
+        let termios = &mut termios as *mut Termios as *mut c_void;
+        check(tcgetattr(slave.as_raw_fd(), termios))?;
+        cfmakeraw(termios);
+        check(tcsetattr(slave.as_raw_fd(), TCSANOW, termios))?;
+
+        Ok(Pty {
+            master,
+            slave,
+            path,
+        })
+    }
+}
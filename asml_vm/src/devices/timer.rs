@@ -0,0 +1,115 @@
+use std::ops::RangeInclusive;
+
+use super::Device;
+
+pub const TIMER_RELOAD: u16 = 0xFFF0;
+pub const TIMER_COUNTER: u16 = 0xFFF2;
+pub const TIMER_CONTROL: u16 = 0xFFF4;
+pub const TIMER_STATUS: u16 = 0xFFF5;
+
+// Control register bits
+pub const TIMER_ENABLE: u8 = 0b01;
+pub const TIMER_IRQ_ENABLE: u8 = 0b10;
+
+// Status register bits
+pub const TIMER_EXPIRED: u8 = 0b01;
+
+/// Programmable interval timer. While enabled, the counter decrements once per
+/// executed instruction. When it underflows the expired flag is set and the
+/// counter is reloaded, so the timer expires every reload + 1 instructions.
+/// Writing any value to the status register clears the expired flag.
+#[derive(Debug, Default)]
+pub struct Timer {
+    reload: u16,
+    counter: u16,
+    control: u8,
+    expired: bool,
+}
+
+impl Timer {
+    pub fn new() -> Self {
+        Timer::default()
+    }
+
+    fn enabled(&self) -> bool {
+        self.control & TIMER_ENABLE > 0
+    }
+}
+
+impl Device for Timer {
+    fn range(&self) -> RangeInclusive<u16> {
+        TIMER_RELOAD..=TIMER_STATUS
+    }
+
+    fn on_read(&mut self, addr: u16) -> u8 {
+        match addr {
+            TIMER_RELOAD => (self.reload >> 8) as u8,
+            a if a == TIMER_RELOAD + 1 => self.reload as u8,
+            TIMER_COUNTER => (self.counter >> 8) as u8,
+            a if a == TIMER_COUNTER + 1 => self.counter as u8,
+            TIMER_CONTROL => self.control,
+            _ => self.expired as u8,
+        }
+    }
+
+    fn on_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            TIMER_RELOAD => self.reload = (self.reload & 0x00FF) | (u16::from(data) << 8),
+            a if a == TIMER_RELOAD + 1 => self.reload = (self.reload & 0xFF00) | u16::from(data),
+            TIMER_CONTROL => {
+                // Starting the timer loads the counter
+                if !self.enabled() && data & TIMER_ENABLE > 0 {
+                    self.counter = self.reload;
+                }
+                self.control = data;
+            }
+            TIMER_STATUS => self.expired = false,
+            _ => {}
+        }
+    }
+
+    fn tick(&mut self) {
+        if !self.enabled() {
+            return;
+        }
+
+        if self.counter == 0 {
+            self.expired = true;
+            self.counter = self.reload;
+        } else {
+            self.counter -= 1;
+        }
+    }
+
+    fn interrupt(&self) -> bool {
+        self.expired && self.control & TIMER_IRQ_ENABLE > 0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_timer() {
+        let mut timer = Timer::new();
+        timer.on_write(TIMER_RELOAD, 0x00);
+        timer.on_write(TIMER_RELOAD + 1, 0x02);
+        timer.on_write(TIMER_CONTROL, TIMER_ENABLE | TIMER_IRQ_ENABLE);
+
+        timer.tick();
+        timer.tick();
+        assert_eq!(timer.on_read(TIMER_COUNTER + 1), 0);
+        assert_eq!(timer.on_read(TIMER_STATUS), 0);
+        assert!(!timer.interrupt());
+
+        timer.tick();
+        assert_eq!(timer.on_read(TIMER_STATUS), TIMER_EXPIRED);
+        assert_eq!(timer.on_read(TIMER_COUNTER + 1), 2);
+        assert!(timer.interrupt());
+
+        timer.on_write(TIMER_STATUS, 0);
+        assert_eq!(timer.on_read(TIMER_STATUS), 0);
+        assert!(!timer.interrupt());
+    }
+}
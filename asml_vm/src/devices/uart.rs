@@ -0,0 +1,335 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream, ToSocketAddrs};
+use std::ops::RangeInclusive;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+
+use super::Device;
+
+pub const UART_DATA: u16 = 0xFFD8;
+pub const UART_STATUS: u16 = 0xFFD9;
+pub const UART_CONTROL: u16 = 0xFFDA;
+
+// Status register bits
+pub const UART_RX_READY: u8 = 0b01;
+pub const UART_CONNECTED: u8 = 0b10;
+
+// Control register bits
+pub const UART_RX_IRQ_ENABLE: u8 = 0b01;
+
+// Received bytes past this are dropped until the program reads some
+const RX_BUFFER_SIZE: usize = 4096;
+
+// Instructions between checks for a waiting client
+const ACCEPT_INTERVAL: u32 = 1024;
+
+type Writer = Box<dyn Write + Send>;
+
+// Sent by the thread reading from the other end
+enum Event {
+    Data(Vec<u8>),
+    Disconnected,
+}
+
+// A TCP port the machine is listening on. It's polled for the next client
+// while none is connected, so it closes when the port is dropped.
+struct Listener {
+    socket: TcpListener,
+    events: Sender<Event>,
+    client: Option<TcpStream>,
+}
+
+impl Listener {
+    // Accept a waiting client and start reading from it
+    fn accept(&mut self) -> Option<Writer> {
+        let (stream, _) = self.socket.accept().ok()?;
+        stream.set_nonblocking(false).ok()?;
+        let reader = stream.try_clone().ok()?;
+        let writer = stream.try_clone().ok()?;
+
+        let tx = self.events.clone();
+        thread::spawn(move || receive(reader, &tx) && tx.send(Event::Disconnected).is_ok());
+        self.client = Some(stream);
+        Some(Box::new(writer))
+    }
+
+    // Wake the reading thread so it lets go of the client
+    fn disconnect(&self) {
+        if let Some(client) = &self.client {
+            let _ = client.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        self.disconnect();
+    }
+}
+
+/// Serial port. Reading the data register returns the next received byte,
+/// or zero if there isn't one, and writing it sends a byte. The status
+/// register reports if a byte is ready and if something is connected to the
+/// other end. Bytes sent while nothing is connected are dropped. If receive
+/// interrupts are enabled in the control register, the port raises an
+/// interrupt until every received byte has been read.
+///
+/// The other end is a TCP connection or a pseudo-terminal, which is read on
+/// a separate thread so the machine never waits for it.
+pub struct Uart {
+    events: Receiver<Event>,
+    listener: Option<Listener>,
+    peer: Option<Writer>,
+    rx: VecDeque<u8>,
+    control: u8,
+    ticks: u32,
+    endpoint: String,
+}
+
+impl Uart {
+    fn new(events: Receiver<Event>, endpoint: String) -> Self {
+        Uart {
+            events,
+            listener: None,
+            peer: None,
+            rx: VecDeque::new(),
+            control: 0,
+            ticks: 0,
+            endpoint,
+        }
+    }
+
+    /// Listen for TCP connections on `addr`. One client is connected at a
+    /// time, the next is accepted after it disconnects.
+    pub fn listen<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        let socket = TcpListener::bind(addr)?;
+        socket.set_nonblocking(true)?;
+        let endpoint = socket.local_addr()?.to_string();
+
+        let (tx, rx) = mpsc::channel();
+        Ok(Uart {
+            listener: Some(Listener {
+                socket,
+                events: tx,
+                client: None,
+            }),
+            ..Uart::new(rx, endpoint)
+        })
+    }
+
+    /// Connect to a TCP listener, such as another machine's serial port.
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        let endpoint = stream.peer_addr()?.to_string();
+        let writer = stream.try_clone()?;
+
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || receive(stream, &tx) && tx.send(Event::Disconnected).is_ok());
+        Ok(Uart {
+            peer: Some(Box::new(writer)),
+            ..Uart::new(rx, endpoint)
+        })
+    }
+
+    /// Create a new pseudo-terminal for the other end. The port is always
+    /// connected.
+    #[cfg(target_os = "linux")]
+    pub fn pty() -> io::Result<Self> {
+        let pty = super::pty::open()?;
+        let endpoint = pty.path.display().to_string();
+
+        let writer = pty.master.try_clone()?;
+
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let _slave = pty.slave;
+            receive(pty.master, &tx)
+        });
+        Ok(Uart {
+            peer: Some(Box::new(writer)),
+            ..Uart::new(rx, endpoint)
+        })
+    }
+
+    /// The address or path of the other end.
+    pub fn endpoint(&self) -> &str {
+        &self.endpoint
+    }
+
+    fn poll(&mut self) {
+        for event in self.events.try_iter() {
+            match event {
+                Event::Data(data) => {
+                    let room = RX_BUFFER_SIZE - self.rx.len();
+                    self.rx.extend(data.into_iter().take(room));
+                }
+                Event::Disconnected => {
+                    self.peer = None;
+                    if let Some(listener) = &mut self.listener {
+                        listener.client = None;
+                    }
+                }
+            }
+        }
+    }
+
+    // The next client is accepted once the last one's reading thread is done
+    fn accept(&mut self) {
+        if let Some(listener) = &mut self.listener
+            && listener.client.is_none()
+            && let Some(peer) = listener.accept()
+        {
+            self.peer = Some(peer);
+        }
+    }
+
+    fn send(&mut self, data: u8) {
+        if let Some(peer) = &mut self.peer
+            && peer.write_all(&[data]).and_then(|_| peer.flush()).is_err()
+        {
+            self.peer = None;
+            if let Some(listener) = &self.listener {
+                listener.disconnect();
+            }
+        }
+    }
+}
+
+// Send everything read to the port until the input ends. Returns false if
+// the port is gone.
+fn receive<R: Read>(mut input: R, tx: &Sender<Event>) -> bool {
+    let mut buf = [0; 256];
+    loop {
+        match input.read(&mut buf) {
+            Ok(0) => return true,
+            Ok(n) => {
+                if tx.send(Event::Data(buf[..n].to_vec())).is_err() {
+                    return false;
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(_) => return true,
+        }
+    }
+}
+
+impl Device for Uart {
+    fn range(&self) -> RangeInclusive<u16> {
+        UART_DATA..=UART_CONTROL
+    }
+
+    fn on_read(&mut self, addr: u16) -> u8 {
+        self.poll();
+        self.accept();
+
+        match addr {
+            UART_DATA => self.rx.pop_front().unwrap_or(0),
+            UART_STATUS => {
+                let mut status = 0;
+                if !self.rx.is_empty() {
+                    status |= UART_RX_READY;
+                }
+                if self.peer.is_some() {
+                    status |= UART_CONNECTED;
+                }
+                status
+            }
+            _ => self.control,
+        }
+    }
+
+    fn on_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            UART_DATA => self.send(data),
+            UART_CONTROL => self.control = data,
+            _ => {}
+        }
+    }
+
+    fn tick(&mut self) {
+        self.poll();
+
+        // Checking for a client on every instruction is too slow
+        self.ticks = self.ticks.wrapping_add(1);
+        if self.ticks.is_multiple_of(ACCEPT_INTERVAL) {
+            self.accept();
+        }
+    }
+
+    fn interrupt(&self) -> bool {
+        !self.rx.is_empty() && self.control & UART_RX_IRQ_ENABLE > 0
+    }
+}
+
+impl fmt::Debug for Uart {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Uart")
+            .field("endpoint", &self.endpoint)
+            .field("connected", &self.peer.is_some())
+            .field("rx", &self.rx.len())
+            .field("control", &self.control)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::devices::Bus;
+
+    #[test]
+    fn test_uart() {
+        use std::time::Duration;
+
+        // The port is connected and reads on other threads
+        fn wait(bus: &mut Bus, status: u8) {
+            for _ in 0..500 {
+                if bus.read(UART_STATUS) == status {
+                    return;
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+            panic!("UART status never became {:02b}", status);
+        }
+
+        let uart = Uart::listen("127.0.0.1:0").unwrap();
+        let endpoint = uart.endpoint().to_string();
+        let mut client = TcpStream::connect(&endpoint).unwrap();
+        let mut bus = Bus::new(0x10000);
+        bus.attach(Box::new(uart));
+        wait(&mut bus, UART_CONNECTED);
+
+        client.write_all(b"hi").unwrap();
+        wait(&mut bus, UART_CONNECTED | UART_RX_READY);
+        assert!(!bus.interrupt_pending());
+        bus.write(UART_CONTROL, UART_RX_IRQ_ENABLE);
+        assert!(bus.interrupt_pending());
+
+        assert_eq!(bus.read(UART_DATA), b'h');
+        assert_eq!(bus.read(UART_DATA), b'i');
+        assert_eq!(bus.read(UART_DATA), 0);
+        assert!(!bus.interrupt_pending());
+
+        bus.write(UART_DATA, b'!');
+        let mut buf = [0; 1];
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"!");
+
+        drop(client);
+        wait(&mut bus, 0);
+
+        // The next client is accepted after the last disconnects
+        let mut client = TcpStream::connect(&endpoint).unwrap();
+        wait(&mut bus, UART_CONNECTED);
+        client.write_all(b"!").unwrap();
+        wait(&mut bus, UART_CONNECTED | UART_RX_READY);
+        assert_eq!(bus.read(UART_DATA), b'!');
+
+        // Dropping the port closes the connection and frees the address
+        drop(bus);
+        assert_eq!(client.read(&mut buf).unwrap(), 0);
+        TcpListener::bind(&endpoint).unwrap();
+    }
+}
@@ -0,0 +1,243 @@
+//! Turns machine code back into ASML source.
+//!
+//! Output uses the same syntax the assembler accepts so it can be compiled
+//! again into identical bytes. Bytes that don't decode to a valid instruction
+//! are written as `FCB` data.
+
+use std::fmt;
+
+use crate::opcodes::{Instruction, Operands};
+use crate::{CodeSection, VM};
+
+// Consecutive data bytes are grouped into one FCB up to this many
+const MAX_DATA_BYTES: usize = 8;
+
+/// A single line of disassembly.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Line {
+    pub addr: u16,
+    pub bytes: Vec<u8>,
+    /// The instruction or FCB statement
+    pub text: String,
+}
+
+impl Line {
+    fn data(addr: u16, bytes: Vec<u8>) -> Line {
+        let values: Vec<String> = bytes.iter().map(|b| format!("0x{:02X}", b)).collect();
+        Line {
+            addr,
+            text: format!("FCB {}", values.join(", ")),
+            bytes,
+        }
+    }
+}
+
+/// Formats the line as source with the address and bytes in a comment:
+/// `LOAD %1 #0x0041         ; 0000  19 01 00 41`
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bytes: Vec<String> = self.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+        write!(
+            f,
+            "{:<24}; {:04X}  {}",
+            self.text,
+            self.addr,
+            bytes.join(" ")
+        )
+    }
+}
+
+// The assembler only accepts registers 0 - D
+fn valid_registers(inst: &Instruction) -> bool {
+    let regs = match inst.opcode.operands() {
+        Operands::Reg | Operands::RegAddr | Operands::RegImm | Operands::RegByte => 1,
+        Operands::RegReg => 2,
+        _ => 0,
+    };
+    inst.args[..regs].iter().all(|r| *r <= 0xD)
+}
+
+fn decode(bytes: &[u8]) -> Option<Instruction> {
+    Instruction::decode(bytes).filter(valid_registers)
+}
+
+/// Disassemble code located at `org`.
+pub fn disassemble(org: u16, bytes: &[u8]) -> Vec<Line> {
+    let mut lines = Vec::new();
+    let mut data: Vec<u8> = Vec::new();
+    let mut data_addr = org;
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let addr = org.wrapping_add(i as u16);
+
+        match decode(&bytes[i..]) {
+            Some(inst) => {
+                if !data.is_empty() {
+                    lines.push(Line::data(data_addr, data.split_off(0)));
+                }
+
+                let size = inst.size();
+                lines.push(Line {
+                    addr,
+                    bytes: bytes[i..i + size].to_vec(),
+                    text: inst.to_string(),
+                });
+                i += size;
+            }
+            None => {
+                if data.is_empty() {
+                    data_addr = addr;
+                }
+                data.push(bytes[i]);
+                if data.len() == MAX_DATA_BYTES {
+                    lines.push(Line::data(data_addr, data.split_off(0)));
+                }
+                i += 1;
+            }
+        }
+    }
+
+    if !data.is_empty() {
+        lines.push(Line::data(data_addr, data));
+    }
+    lines
+}
+
+/// Disassemble a program into ASML source. Sections that follow each other
+/// in memory are joined so instructions can span them, and each run of
+/// sections starts with an ORG. Sections in other memory banks come last,
+/// each bank starting with a BANK.
+pub fn disassemble_code(code: &[CodeSection]) -> String {
+    let mut sections: Vec<&CodeSection> = code.iter().filter(|s| !s.code.is_empty()).collect();
+    sections.sort_by_key(|s| (s.bank, s.org));
+
+    let mut joined: Vec<(u8, u16, Vec<u8>)> = Vec::new();
+    for section in sections {
+        match joined.last_mut() {
+            Some((bank, org, bytes))
+                if *bank == section.bank
+                    && usize::from(*org) + bytes.len() == usize::from(section.org) =>
+            {
+                bytes.extend_from_slice(&section.code)
+            }
+            _ => joined.push((section.bank, section.org, section.code.clone())),
+        }
+    }
+
+    let mut src = String::new();
+    let mut cur_bank = 0;
+    for (bank, org, bytes) in joined {
+        if bank != cur_bank {
+            src.push_str(&format!("    BANK {}\n", bank));
+            cur_bank = bank;
+        }
+        src.push_str(&format!("    ORG 0x{:04X}\n", org));
+        for line in disassemble(org, &bytes) {
+            src.push_str(&format!("    {}\n", line));
+        }
+    }
+    src
+}
+
+impl VM {
+    /// Disassemble `count` instructions from memory starting at `addr`.
+    /// Memory mapped devices aren't read.
+    pub fn disassemble(&self, addr: u16, count: usize) -> Vec<Line> {
+        let mut lines = Vec::with_capacity(count);
+        let mut addr = addr;
+
+        for _ in 0..count {
+            let bytes: Vec<u8> = (0..4)
+                .map(|i| self.bus.peek(addr.wrapping_add(i)))
+                .collect();
+            let line = match decode(&bytes) {
+                Some(inst) => Line {
+                    addr,
+                    bytes: bytes[..inst.size()].to_vec(),
+                    text: inst.to_string(),
+                },
+                None => Line::data(addr, vec![bytes[0]]),
+            };
+
+            addr = addr.wrapping_add(line.bytes.len() as u16);
+            lines.push(line);
+        }
+        lines
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::opcodes::OpCode as opc;
+
+    #[test]
+    fn test_disassemble() {
+        let code = [
+            &[opc::LOADI as u8, 1, 0x00, 0x41][..],
+            &[opc::STRA as u8, 1, 0xFF, 0xFD],
+            &[opc::ROTR as u8, 0xA, 4],
+            // Invalid opcodes and a PUSH of register 0x20, which also
+            // happens to be the DEBUG opcode
+            &[0xFF, 0xFE, opc::PUSH as u8, 0x20],
+            &[opc::HALT as u8],
+            &[opc::LOADI as u8, 1],
+        ]
+        .concat();
+
+        let text: Vec<(u16, String)> = disassemble(0x100, &code)
+            .into_iter()
+            .map(|l| (l.addr, l.text))
+            .collect();
+
+        assert_eq!(
+            text,
+            vec![
+                (0x100, "LOAD %1 #0x0041".to_owned()),
+                (0x104, "STR %1 0xFFFD".to_owned()),
+                (0x108, "ROTR %A #0x04".to_owned()),
+                (0x10B, "FCB 0xFF, 0xFE, 0x1F".to_owned()),
+                (0x10E, "DEBUG".to_owned()),
+                (0x10F, "HALT".to_owned()),
+                (0x110, "FCB 0x19, 0x01".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_disassemble_code() {
+        let code = vec![
+            CodeSection {
+                org: 0x10,
+                bank: 0,
+                code: vec![opc::JMPA as u8, 0x00],
+            },
+            CodeSection {
+                org: 0x12,
+                bank: 0,
+                code: vec![0x10],
+            },
+            CodeSection {
+                org: 0,
+                bank: 0,
+                code: vec![],
+            },
+            CodeSection {
+                org: 0xFFFE,
+                bank: 0,
+                code: vec![0x00, 0x10],
+            },
+        ];
+
+        assert_eq!(
+            disassemble_code(&code),
+            "    ORG 0x0010
+    JMPA 0x0010             ; 0010  14 00 10
+    ORG 0xFFFE
+    NOOP                    ; FFFE  00
+    FCB 0x10                ; FFFF  10
+"
+        );
+    }
+}
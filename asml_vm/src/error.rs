@@ -0,0 +1,120 @@
+use std::error;
+use std::fmt;
+use std::time::Duration;
+
+use crate::protection::Access;
+use crate::{FLAG_CARRY, FLAG_INTERRUPT, FLAG_NEGATIVE, FLAG_OVERFLOW, FLAG_ZERO};
+
+/// The state of the machine at the instruction that caused an error.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MachineState {
+    /// Address of the faulting instruction
+    pub pc: u16,
+    pub opcode: u8,
+    pub sp: u16,
+    pub flags: u8,
+    pub registers: Vec<u8>,
+}
+
+impl fmt::Display for MachineState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "0    1    2    3    4    5    6    7    8    9")?;
+        for reg in &self.registers {
+            write!(f, "0x{:02X} ", reg)?;
+        }
+        writeln!(
+            f,
+            "\nPC: 0x{:04X} | SP: 0x{:04X} | Opcode: 0x{:02X}",
+            self.pc, self.sp, self.opcode
+        )?;
+        write!(
+            f,
+            "Flags: Z={} C={} N={} V={} I={}",
+            (self.flags & FLAG_ZERO > 0) as u8,
+            (self.flags & FLAG_CARRY > 0) as u8,
+            (self.flags & FLAG_NEGATIVE > 0) as u8,
+            (self.flags & FLAG_OVERFLOW > 0) as u8,
+            (self.flags & FLAG_INTERRUPT > 0) as u8
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum VmError {
+    UnknownOpcode(MachineState),
+    InvalidRegister(u8, MachineState),
+    StackOverflow(MachineState),
+    StackUnderflow(MachineState),
+    PcOverflow(MachineState),
+    MemoryFault(u16, MachineState),
+    /// An access to an address forbidden by a protected region
+    AccessViolation(u16, Access, MachineState),
+    DivideByZero(MachineState),
+    /// The instruction limit was reached before the machine halted
+    StepLimit(u64, MachineState),
+    /// The time limit was reached before the machine halted
+    TimeLimit(Duration, MachineState),
+}
+
+impl VmError {
+    pub fn state(&self) -> &MachineState {
+        match self {
+            VmError::UnknownOpcode(s) => s,
+            VmError::InvalidRegister(_, s) => s,
+            VmError::StackOverflow(s) => s,
+            VmError::StackUnderflow(s) => s,
+            VmError::PcOverflow(s) => s,
+            VmError::MemoryFault(_, s) => s,
+            VmError::AccessViolation(_, _, s) => s,
+            VmError::DivideByZero(s) => s,
+            VmError::StepLimit(_, s) => s,
+            VmError::TimeLimit(_, s) => s,
+        }
+    }
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VmError::UnknownOpcode(s) => {
+                write!(f, "unknown opcode 0x{:02X} at 0x{:04X}", s.opcode, s.pc)
+            }
+            VmError::InvalidRegister(r, s) => {
+                write!(f, "invalid register 0x{:02X} at 0x{:04X}", r, s.pc)
+            }
+            VmError::StackOverflow(s) => {
+                write!(f, "stack overflow at 0x{:04X}, SP 0x{:04X}", s.pc, s.sp)
+            }
+            VmError::StackUnderflow(s) => {
+                write!(f, "stack underflow at 0x{:04X}, SP 0x{:04X}", s.pc, s.sp)
+            }
+            VmError::PcOverflow(s) => {
+                write!(f, "program counter ran past 0xFFFF at 0x{:04X}", s.pc)
+            }
+            VmError::MemoryFault(addr, s) => write!(
+                f,
+                "invalid memory access to 0x{:04X} at 0x{:04X}",
+                addr, s.pc
+            ),
+            VmError::AccessViolation(addr, Access::Write, s) => write!(
+                f,
+                "write to read-only address 0x{:04X} at 0x{:04X}",
+                addr, s.pc
+            ),
+            VmError::AccessViolation(addr, Access::Execute, s) => write!(
+                f,
+                "execute from no-execute address 0x{:04X} at 0x{:04X}",
+                addr, s.pc
+            ),
+            VmError::DivideByZero(s) => write!(f, "division by zero at 0x{:04X}", s.pc),
+            VmError::StepLimit(n, s) => {
+                write!(f, "instruction limit of {} reached at 0x{:04X}", n, s.pc)
+            }
+            VmError::TimeLimit(t, s) => {
+                write!(f, "time limit of {:?} reached at 0x{:04X}", t, s.pc)
+            }
+        }
+    }
+}
+
+impl error::Error for VmError {}
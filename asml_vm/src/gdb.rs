@@ -0,0 +1,732 @@
+//! GDB remote serial protocol stub.
+//!
+//! Lets GDB, or any front end speaking the protocol, debug a `VM` over TCP.
+//! The stub supports reading and writing registers and memory, single
+//! stepping, continuing, interrupting a running program and software
+//! breakpoints and watchpoints.
+//!
+//! Registers are described to the client with a target description:
+//!
+//! | Number | Name    | Size   |
+//! |--------|---------|--------|
+//! | 0 - 9  | `r0-r9` | 8 bit  |
+//! | 10     | `pc`    | 16 bit |
+//! | 11     | `sp`    | 16 bit |
+//! | 12     | `flags` | 8 bit  |
+//!
+//! Like the VM itself, 16 bit registers are sent big endian.
+
+use std::fmt::Write as _;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+
+use crate::breakpoints::WatchKind;
+use crate::{NUM_OF_REGISTERS, StepOutcome, VM, VmError};
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.asml.core">
+    <reg name="r0" bitsize="8" type="uint8" regnum="0"/>
+    <reg name="r1" bitsize="8" type="uint8"/>
+    <reg name="r2" bitsize="8" type="uint8"/>
+    <reg name="r3" bitsize="8" type="uint8"/>
+    <reg name="r4" bitsize="8" type="uint8"/>
+    <reg name="r5" bitsize="8" type="uint8"/>
+    <reg name="r6" bitsize="8" type="uint8"/>
+    <reg name="r7" bitsize="8" type="uint8"/>
+    <reg name="r8" bitsize="8" type="uint8"/>
+    <reg name="r9" bitsize="8" type="uint8"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+    <reg name="sp" bitsize="16" type="data_ptr"/>
+    <reg name="flags" bitsize="8" type="uint8"/>
+  </feature>
+</target>
+"#;
+
+const PACKET_SIZE: usize = 0x1000;
+
+// Register numbers after the general purpose registers
+const REG_PC: usize = NUM_OF_REGISTERS;
+const REG_SP: usize = NUM_OF_REGISTERS + 1;
+const REG_FLAGS: usize = NUM_OF_REGISTERS + 2;
+
+// Interrupt character sent by the client to stop a running program
+const INTERRUPT: u8 = 0x03;
+
+// Instructions executed between checks for an interrupt
+const INTERRUPT_CHECK: u64 = 1024;
+
+// Signals used in stop replies
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGFPE: u8 = 8;
+const SIGSEGV: u8 = 11;
+const SIGXCPU: u8 = 24;
+
+/// A byte stream to a GDB client.
+pub trait Connection: Read + Write {
+    /// Read whatever is available without waiting, returning 0 if nothing
+    /// is.
+    fn read_available(&mut self, buf: &mut [u8]) -> io::Result<usize>;
+}
+
+impl Connection for TcpStream {
+    fn read_available(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.set_nonblocking(true)?;
+        let res = self.read(buf);
+        self.set_nonblocking(false)?;
+
+        match res {
+            Ok(0) => Err(io::ErrorKind::UnexpectedEof.into()),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(0),
+            res => res,
+        }
+    }
+}
+
+/// How a GDB session ended.
+#[derive(Debug)]
+pub enum SessionEnd {
+    /// The program halted
+    Halted,
+    /// The client detached, the program should keep running
+    Detached,
+    /// The client killed the program
+    Killed,
+    /// The program crashed. The client has been told it was terminated.
+    Crashed(VmError),
+}
+
+/// Wait for a single client on `addr` and debug `vm` until the session ends.
+pub fn serve<A: ToSocketAddrs>(vm: &mut VM, addr: A) -> io::Result<SessionEnd> {
+    let listener = TcpListener::bind(addr)?;
+    let (stream, _) = listener.accept()?;
+    stream.set_nodelay(true)?;
+
+    GdbStub::new(vm, stream).run()
+}
+
+// The result of handling one packet
+enum Reply {
+    Packet(String),
+    End(SessionEnd),
+}
+
+pub struct GdbStub<'a, C> {
+    vm: &'a mut VM,
+    conn: C,
+    // Received bytes that haven't been handled yet
+    pending: Vec<u8>,
+    no_ack: bool,
+}
+
+impl<'a, C: Connection> GdbStub<'a, C> {
+    pub fn new(vm: &'a mut VM, conn: C) -> Self {
+        GdbStub {
+            vm,
+            conn,
+            pending: Vec::new(),
+            no_ack: false,
+        }
+    }
+
+    /// Handle packets until the session ends.
+    pub fn run(mut self) -> io::Result<SessionEnd> {
+        loop {
+            let packet = match self.read_packet()? {
+                Some(packet) => packet,
+                // The client disconnected without detaching
+                None => return Ok(SessionEnd::Killed),
+            };
+
+            match self.handle(&packet) {
+                Reply::Packet(reply) => self.send(&reply)?,
+                Reply::End(end) => {
+                    match &end {
+                        SessionEnd::Halted => self.send("W00")?,
+                        SessionEnd::Crashed(e) => self.send(&format!("X{:02x}", signal(e)))?,
+                        _ => {}
+                    }
+                    return Ok(end);
+                }
+            }
+        }
+    }
+
+    // Read more bytes into the pending buffer, returning false at the end of
+    // the stream
+    fn fill(&mut self) -> io::Result<bool> {
+        let mut buf = [0; 1024];
+        let n = self.conn.read(&mut buf)?;
+        self.pending.extend_from_slice(&buf[..n]);
+        Ok(n > 0)
+    }
+
+    // Read the next packet's data, skipping acks and interrupts
+    fn read_packet(&mut self) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            if let Some(start) = self.pending.iter().position(|b| *b == b'$') {
+                self.pending.drain(..start);
+
+                // The data is followed by # and a 2 digit checksum
+                if let Some(end) = self.pending.iter().position(|b| *b == b'#')
+                    && self.pending.len() >= end + 3
+                {
+                    let data = self.pending[1..end].to_vec();
+                    let checksum = std::str::from_utf8(&self.pending[end + 1..end + 3])
+                        .ok()
+                        .and_then(|s| u8::from_str_radix(s, 16).ok());
+                    self.pending.drain(..end + 3);
+
+                    if self.no_ack {
+                        return Ok(Some(data));
+                    }
+                    if checksum == Some(sum(&data)) {
+                        self.conn.write_all(b"+")?;
+                        return Ok(Some(data));
+                    }
+                    self.conn.write_all(b"-")?;
+                    continue;
+                }
+            } else {
+                self.pending.clear();
+            }
+
+            if !self.fill()? {
+                return Ok(None);
+            }
+        }
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let mut packet = Vec::with_capacity(data.len() + 4);
+        packet.push(b'$');
+        for b in data.bytes() {
+            match b {
+                b'$' | b'#' | b'}' | b'*' => packet.extend_from_slice(&[b'}', b ^ 0x20]),
+                _ => packet.push(b),
+            }
+        }
+        let checksum = sum(&packet[1..]);
+        write!(packet, "#{:02x}", checksum)?;
+
+        self.conn.write_all(&packet)?;
+        self.conn.flush()
+    }
+
+    // Check for an interrupt from the client while the program is running
+    fn poll_interrupt(&mut self) -> io::Result<bool> {
+        let mut buf = [0; 64];
+        let n = self.conn.read_available(&mut buf)?;
+
+        let mut interrupted = false;
+        for b in &buf[..n] {
+            if *b == INTERRUPT {
+                interrupted = true;
+            } else {
+                self.pending.push(*b);
+            }
+        }
+        Ok(interrupted)
+    }
+
+    fn handle(&mut self, packet: &[u8]) -> Reply {
+        let packet = String::from_utf8_lossy(packet);
+        let (cmd, args) = packet.split_at(packet.len().min(1));
+
+        let reply = match cmd {
+            "?" => format!("S{:02x}", SIGTRAP),
+            "g" => self.read_registers(),
+            "G" => self.write_registers(args),
+            "p" => self.read_register(args),
+            "P" => self.write_register(args),
+            "m" => self.read_memory(args),
+            "M" => self.write_memory(args),
+            "s" => return self.step(args),
+            "c" => return self.resume(args),
+            "Z" => self.breakpoint(args, true),
+            "z" => self.breakpoint(args, false),
+            "H" => "OK".to_owned(),
+            "T" => "OK".to_owned(),
+            "D" => {
+                // The client waits for the reply before disconnecting
+                let _ = self.send("OK");
+                return Reply::End(SessionEnd::Detached);
+            }
+            "k" => return Reply::End(SessionEnd::Killed),
+            "q" | "Q" => self.query(&packet),
+            _ => String::new(),
+        };
+        Reply::Packet(reply)
+    }
+
+    fn query(&mut self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            format!(
+                "PacketSize={:x};qXfer:features:read+;swbreak+;QStartNoAckMode+",
+                PACKET_SIZE
+            )
+        } else if packet == "QStartNoAckMode" {
+            // The reply is still acknowledged, only later packets aren't
+            self.no_ack = true;
+            "OK".to_owned()
+        } else if let Some(args) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            read_xfer(TARGET_XML, args)
+        } else if packet == "qAttached" {
+            "1".to_owned()
+        } else if packet == "qC" {
+            "QC1".to_owned()
+        } else if packet == "qfThreadInfo" {
+            "m1".to_owned()
+        } else if packet == "qsThreadInfo" {
+            "l".to_owned()
+        } else {
+            String::new()
+        }
+    }
+
+    fn register_bytes(&self, reg: usize) -> Option<Vec<u8>> {
+        match reg {
+            0..NUM_OF_REGISTERS => Some(vec![self.vm.registers()[reg]]),
+            REG_PC => Some(self.vm.pc().to_be_bytes().to_vec()),
+            REG_SP => Some(self.vm.sp().to_be_bytes().to_vec()),
+            REG_FLAGS => Some(vec![self.vm.flags()]),
+            _ => None,
+        }
+    }
+
+    // Returns false if the register doesn't exist or the value is the wrong
+    // size
+    fn set_register_bytes(&mut self, reg: usize, bytes: &[u8]) -> bool {
+        match (reg, bytes) {
+            (0..NUM_OF_REGISTERS, [b]) => self.vm.set_register(reg as u8, u16::from(*b)).is_ok(),
+            (REG_PC, [hi, lo]) => {
+                self.vm.set_pc(u16::from_be_bytes([*hi, *lo]));
+                true
+            }
+            (REG_SP, [hi, lo]) => {
+                self.vm.set_sp(u16::from_be_bytes([*hi, *lo]));
+                true
+            }
+            (REG_FLAGS, [b]) => {
+                self.vm.set_flags(*b);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn read_registers(&self) -> String {
+        (0..=REG_FLAGS)
+            .filter_map(|reg| self.register_bytes(reg))
+            .map(|bytes| to_hex(&bytes))
+            .collect()
+    }
+
+    fn write_registers(&mut self, args: &str) -> String {
+        let bytes = match from_hex(args) {
+            Some(bytes) if bytes.len() == NUM_OF_REGISTERS + 5 => bytes,
+            _ => return error(),
+        };
+
+        let mut i = 0;
+        for reg in 0..=REG_FLAGS {
+            let size = if reg == REG_PC || reg == REG_SP { 2 } else { 1 };
+            self.set_register_bytes(reg, &bytes[i..i + size]);
+            i += size;
+        }
+        "OK".to_owned()
+    }
+
+    // p REG
+    fn read_register(&self, args: &str) -> String {
+        usize::from_str_radix(args, 16)
+            .ok()
+            .and_then(|reg| self.register_bytes(reg))
+            .map_or_else(error, |bytes| to_hex(&bytes))
+    }
+
+    // P REG=VALUE
+    fn write_register(&mut self, args: &str) -> String {
+        let parsed = args.split_once('=').and_then(|(reg, value)| {
+            Some((usize::from_str_radix(reg, 16).ok()?, from_hex(value)?))
+        });
+
+        match parsed {
+            Some((reg, bytes)) if self.set_register_bytes(reg, &bytes) => "OK".to_owned(),
+            _ => error(),
+        }
+    }
+
+    // m ADDR,LENGTH
+    fn read_memory(&self, args: &str) -> String {
+        match parse_range(args) {
+            Some((addr, len)) => {
+                let bytes: Vec<u8> = (0..len.min(PACKET_SIZE / 2))
+                    .map_while(|i| addr.checked_add(i as u16))
+                    .map(|addr| self.vm.peek(addr))
+                    .collect();
+                to_hex(&bytes)
+            }
+            None => error(),
+        }
+    }
+
+    // M ADDR,LENGTH:DATA
+    fn write_memory(&mut self, args: &str) -> String {
+        let parsed = args
+            .split_once(':')
+            .and_then(|(range, data)| Some((parse_range(range)?, from_hex(data)?)));
+
+        match parsed {
+            Some(((addr, len), bytes)) if bytes.len() == len => {
+                for (i, b) in bytes.into_iter().enumerate() {
+                    self.vm.poke(addr.wrapping_add(i as u16), b);
+                }
+                "OK".to_owned()
+            }
+            _ => error(),
+        }
+    }
+
+    // Z/z TYPE,ADDR,KIND
+    fn breakpoint(&mut self, args: &str, insert: bool) -> String {
+        let mut parts = args.split(',');
+        let kind = parts.next();
+        let range = parts.next().and_then(|addr| {
+            let len = parts
+                .next()
+                .and_then(|k| usize::from_str_radix(k, 16).ok())?;
+            Some((u16::from_str_radix(addr, 16).ok()?, len.max(1)))
+        });
+
+        let (addr, len) = match range {
+            Some(range) => range,
+            None => return error(),
+        };
+
+        let watch = match kind {
+            // Software and hardware breakpoints are the same for the VM
+            Some("0") | Some("1") => {
+                if insert {
+                    self.vm.add_breakpoint(addr, None);
+                } else {
+                    self.vm.remove_breakpoint(addr);
+                }
+                return "OK".to_owned();
+            }
+            Some("2") => WatchKind::Write,
+            Some("3") => WatchKind::Read,
+            Some("4") => WatchKind::Access,
+            _ => return String::new(),
+        };
+
+        let end = addr.saturating_add((len - 1) as u16);
+        if insert {
+            self.vm.add_watchpoint(addr..=end, watch);
+        } else if let Some(i) = self
+            .vm
+            .watchpoints()
+            .iter()
+            .position(|wp| wp.kind == watch && wp.range == (addr..=end))
+        {
+            self.vm.remove_watchpoint(i);
+        }
+        "OK".to_owned()
+    }
+
+    // s [ADDR]
+    fn step(&mut self, args: &str) -> Reply {
+        if let Ok(addr) = u16::from_str_radix(args, 16) {
+            self.vm.set_pc(addr);
+        }
+
+        // Stopping at a breakpoint before executing anything isn't a step
+        let pc = self.vm.pc();
+        let outcome = match self.vm.step() {
+            Ok(StepOutcome::Breakpoint(addr)) if addr == pc => self.vm.step(),
+            outcome => outcome,
+        };
+
+        match outcome {
+            Ok(StepOutcome::Halted) => Reply::End(SessionEnd::Halted),
+            Ok(StepOutcome::Watchpoint(hit)) => Reply::Packet(watch_stop(hit.kind, hit.addr)),
+            Ok(_) => Reply::Packet(format!("S{:02x}", SIGTRAP)),
+            Err(e) => Reply::End(SessionEnd::Crashed(e)),
+        }
+    }
+
+    // c [ADDR]
+    fn resume(&mut self, args: &str) -> Reply {
+        if let Ok(addr) = u16::from_str_radix(args, 16) {
+            self.vm.set_pc(addr);
+        }
+
+        let mut steps: u64 = 0;
+        loop {
+            let outcome = match self.vm.step() {
+                Ok(outcome) => outcome,
+                Err(e) => return Reply::End(SessionEnd::Crashed(e)),
+            };
+
+            match outcome {
+                StepOutcome::Halted => return Reply::End(SessionEnd::Halted),
+                StepOutcome::Breakpoint(_) => {
+                    return Reply::Packet(format!("T{:02x}swbreak:;", SIGTRAP));
+                }
+                StepOutcome::Debug => return Reply::Packet(format!("S{:02x}", SIGTRAP)),
+                StepOutcome::Watchpoint(hit) => {
+                    return Reply::Packet(watch_stop(hit.kind, hit.addr));
+                }
+                StepOutcome::Continue | StepOutcome::DeviceWrite(_) => {}
+            }
+
+            steps += 1;
+            if steps.is_multiple_of(INTERRUPT_CHECK) {
+                match self.poll_interrupt() {
+                    Ok(true) => return Reply::Packet(format!("S{:02x}", SIGINT)),
+                    Ok(false) => {}
+                    // The client is gone, stop the program
+                    Err(_) => return Reply::End(SessionEnd::Killed),
+                }
+            }
+        }
+    }
+}
+
+fn sum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, b| sum.wrapping_add(*b))
+}
+
+fn error() -> String {
+    "E01".to_owned()
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    let mut s = String::with_capacity(bytes.len() * 2);
+    for b in bytes {
+        let _ = write!(s, "{:02x}", b);
+    }
+    s
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+// ADDR,LENGTH
+fn parse_range(s: &str) -> Option<(u16, usize)> {
+    let (addr, len) = s.split_once(',')?;
+    Some((
+        u16::from_str_radix(addr, 16).ok()?,
+        usize::from_str_radix(len, 16).ok()?,
+    ))
+}
+
+// OFFSET,LENGTH of a qXfer read
+fn read_xfer(data: &str, args: &str) -> String {
+    let (offset, len) = match args.split_once(',').and_then(|(offset, len)| {
+        Some((
+            usize::from_str_radix(offset, 16).ok()?,
+            usize::from_str_radix(len, 16).ok()?,
+        ))
+    }) {
+        Some(range) => range,
+        None => return error(),
+    };
+
+    if offset >= data.len() {
+        return "l".to_owned();
+    }
+    let end = data.len().min(offset + len);
+    let prefix = if end == data.len() { 'l' } else { 'm' };
+    format!("{}{}", prefix, &data[offset..end])
+}
+
+fn watch_stop(kind: WatchKind, addr: u16) -> String {
+    let name = match kind {
+        WatchKind::Write => "watch",
+        WatchKind::Read => "rwatch",
+        WatchKind::Access => "awatch",
+    };
+    format!("T{:02x}{}:{:x};", SIGTRAP, name, addr)
+}
+
+// The signal reported to the client when the program crashes
+fn signal(e: &VmError) -> u8 {
+    match e {
+        VmError::UnknownOpcode(_) | VmError::InvalidRegister(..) => SIGILL,
+        VmError::DivideByZero(_) => SIGFPE,
+        VmError::StepLimit(..) | VmError::TimeLimit(..) => SIGXCPU,
+        _ => SIGSEGV,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::CodeSection;
+    use crate::opcodes::OpCode as opc;
+
+    struct MockConnection {
+        input: io::Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for MockConnection {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for MockConnection {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Connection for MockConnection {
+        fn read_available(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            Ok(0)
+        }
+    }
+
+    // Run a session with the client sending `packets`, returning how it
+    // ended and the replies
+    fn session(vm: &mut VM, packets: &[&str]) -> (SessionEnd, Vec<String>) {
+        let input: String = packets
+            .iter()
+            .map(|p| format!("${}#{:02x}", p, sum(p.as_bytes())))
+            .collect();
+        let mut conn = MockConnection {
+            input: io::Cursor::new(input.into_bytes()),
+            output: Vec::new(),
+        };
+
+        let end = GdbStub::new(vm, &mut conn).run().unwrap();
+        let output = String::from_utf8(conn.output).unwrap();
+        let replies = output
+            .split('$')
+            .skip(1)
+            .map(|p| p.split('#').next().unwrap().to_owned())
+            .collect();
+        (end, replies)
+    }
+
+    impl Connection for &mut MockConnection {
+        fn read_available(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            (**self).read_available(buf)
+        }
+    }
+
+    // Prints "A" in a loop
+    fn loop_vm() -> VM {
+        let mut vm = VM::new();
+        vm.install_code(&[CodeSection {
+            org: 0,
+            bank: 0,
+            code: [
+                &[opc::LOADI as u8, 1, 0x00, b'A'][..],
+                &[opc::STRA as u8, 1, 0xFF, 0xFD],
+                &[opc::JMPA as u8, 0x00, 0x04],
+            ]
+            .concat(),
+        }]);
+        vm
+    }
+
+    #[test]
+    fn test_registers_and_memory() {
+        let mut vm = loop_vm();
+        let (end, replies) = session(
+            &mut vm,
+            &[
+                "?",
+                "s",
+                "g",
+                "Pa=0004",
+                "p1",
+                "P1=42",
+                "m0,4",
+                "M40,2:4869",
+                "D",
+            ],
+        );
+
+        assert!(matches!(end, SessionEnd::Detached));
+        assert_eq!(
+            replies,
+            vec![
+                "S05",
+                "S05",
+                "004100000000000000000004000000",
+                "OK",
+                "41",
+                "OK",
+                "19010041",
+                "OK",
+                "OK",
+            ]
+        );
+        assert_eq!(vm.pc(), 4);
+        assert_eq!(vm.registers()[1], 0x42);
+        assert_eq!(vm.peek(0x40), b'H');
+        assert_eq!(vm.peek(0x41), b'i');
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        let mut vm = loop_vm();
+        let (end, replies) = session(
+            &mut vm,
+            &["Z0,8,1", "c", "pa", "c", "z0,8,1", "Z2,fffd,1", "c", "k"],
+        );
+
+        assert!(matches!(end, SessionEnd::Killed));
+        assert_eq!(
+            replies,
+            vec![
+                "OK",
+                "T05swbreak:;",
+                "0008",
+                "T05swbreak:;",
+                "OK",
+                "OK",
+                "T05watch:fffd;"
+            ]
+        );
+        assert_eq!(vm.output(), "AAA");
+    }
+
+    #[test]
+    fn test_halt() {
+        let mut vm = VM::new();
+        vm.install_code(&[CodeSection {
+            org: 0,
+            bank: 0,
+            code: vec![opc::NOOP as u8, opc::HALT as u8],
+        }]);
+        let (end, replies) = session(&mut vm, &["qSupported:swbreak+", "c"]);
+
+        assert!(matches!(end, SessionEnd::Halted));
+        assert_eq!(
+            replies,
+            vec![
+                "PacketSize=1000;qXfer:features:read+;swbreak+;QStartNoAckMode+",
+                "W00"
+            ]
+        );
+    }
+}
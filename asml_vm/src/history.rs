@@ -0,0 +1,100 @@
+use std::collections::VecDeque;
+
+/// The machine state needed to undo one instruction.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Delta {
+    pub pc: u16,
+    pub sp: u16,
+    pub flags: u8,
+    pub halted: bool,
+    pub registers: Vec<u8>,
+    /// Selected memory bank, None without banking
+    pub bank: Option<u8>,
+    /// Previous value of each memory byte written, in write order. Bytes in
+    /// the bank window are kept with the bank they were written to.
+    pub memory: Vec<(Option<u8>, u16, u8)>,
+}
+
+/// A bounded ring buffer of undo deltas. When full the oldest delta is
+/// dropped. A limit of 0 disables recording.
+#[derive(Debug)]
+pub(crate) struct History {
+    deltas: VecDeque<Delta>,
+    limit: usize,
+}
+
+impl History {
+    pub fn new(limit: usize) -> History {
+        History {
+            deltas: VecDeque::new(),
+            limit,
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.limit > 0
+    }
+
+    pub fn set_limit(&mut self, limit: usize) {
+        self.limit = limit;
+        while self.deltas.len() > limit {
+            self.deltas.pop_front();
+        }
+    }
+
+    pub fn push(&mut self, delta: Delta) {
+        if !self.enabled() {
+            return;
+        }
+        if self.deltas.len() == self.limit {
+            self.deltas.pop_front();
+        }
+        self.deltas.push_back(delta);
+    }
+
+    pub fn pop(&mut self) -> Option<Delta> {
+        self.deltas.pop_back()
+    }
+
+    pub fn len(&self) -> usize {
+        self.deltas.len()
+    }
+
+    pub fn clear(&mut self) {
+        self.deltas.clear();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn delta(pc: u16) -> Delta {
+        Delta {
+            pc,
+            sp: 0,
+            flags: 0,
+            halted: false,
+            registers: vec![],
+            bank: None,
+            memory: vec![],
+        }
+    }
+
+    #[test]
+    fn test_history_limit() {
+        let mut history = History::new(2);
+        history.push(delta(1));
+        history.push(delta(2));
+        history.push(delta(3));
+
+        assert_eq!(history.len(), 2);
+        assert_eq!(history.pop().unwrap().pc, 3);
+        assert_eq!(history.pop().unwrap().pc, 2);
+        assert_eq!(history.pop(), None);
+
+        history.set_limit(0);
+        history.push(delta(4));
+        assert_eq!(history.len(), 0);
+    }
+}
@@ -1,13 +1,37 @@
+pub mod breakpoints;
+pub mod debugger;
+pub mod devices;
+pub mod disasm;
+pub mod error;
+pub mod gdb;
+mod history;
+pub mod observer;
 pub mod opcodes;
-
-use std::io;
-use std::process;
-
-use crate::opcodes::OpCode as opc;
+pub mod profile;
+pub mod protection;
+pub mod snapshot;
+pub mod symbols;
+pub mod trace;
+
+use std::cell::RefCell;
+use std::fmt;
+use std::io::{self, Write};
+use std::ops::RangeInclusive;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use crate::breakpoints::{Breakpoint, Condition, WatchHit, WatchKind, Watchpoint};
+use crate::devices::{BANK_WINDOW, Bus, Device, Printer, Timer};
+pub use crate::error::{MachineState, VmError};
+use crate::history::{Delta, History};
+use crate::observer::Observer;
+use crate::opcodes::{Instruction, OpCode as opc};
+use crate::profile::Profile;
+use crate::protection::{Access, Region};
+use crate::trace::Tracer;
 
 const NUM_OF_MEMORY_CELLS: usize = 65536;
 const NUM_OF_REGISTERS: usize = 10;
-const PRINTER_ADDR: usize = NUM_OF_MEMORY_CELLS - 3;
 
 // Double width registers
 const REG_A: u8 = 0xA;
@@ -15,6 +39,18 @@ const REG_B: u8 = 0xB;
 const REG_C: u8 = 0xC;
 const REG_D: u8 = 0xD;
 
+// Status flags
+pub const FLAG_ZERO: u8 = 0b0001;
+pub const FLAG_CARRY: u8 = 0b0010;
+pub const FLAG_NEGATIVE: u8 = 0b0100;
+pub const FLAG_OVERFLOW: u8 = 0b1000;
+pub const FLAG_INTERRUPT: u8 = 0b1_0000;
+
+const DEFAULT_HISTORY_LIMIT: usize = 10_000;
+
+const RESET_VECTOR: u16 = 0xFFFE;
+const IRQ_VECTOR: u16 = 0xFFFB;
+
 fn is_double_reg(r: u8) -> bool {
     (REG_A..=REG_D).contains(&r)
 }
@@ -27,74 +63,171 @@ fn reg_width(r: u8) -> u8 {
     }
 }
 
+fn width_mask(width: u8) -> u16 {
+    if width == 2 { 0xFFFF } else { 0xFF }
+}
+
+fn width_sign(width: u8) -> u16 {
+    if width == 2 { 0x8000 } else { 0x80 }
+}
+
+type Result<T> = std::result::Result<T, VmError>;
+
 pub type Code = Vec<CodeSection>;
 
 pub struct CodeSection {
     pub org: u16,
+    /// Memory bank of code in the bank window, 0 for everything else
+    pub bank: u8,
     pub code: Vec<u8>,
 }
 
-#[derive(Debug, Default)]
+/// The result of executing a single instruction.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StepOutcome {
+    /// The instruction executed normally
+    Continue,
+    /// The machine executed a HALT instruction
+    Halted,
+    /// The machine executed a DEBUG instruction
+    Debug,
+    /// The instruction wrote to the device at the given address
+    DeviceWrite(u16),
+    /// Execution stopped before the instruction at the given address
+    Breakpoint(u16),
+    /// The instruction accessed memory covered by a watchpoint
+    Watchpoint(WatchHit),
+}
+
+#[derive(Debug)]
 pub struct VM {
     registers: Vec<u8>,
-    memory: Vec<u8>,
+    bus: Bus,
+    printer: Rc<RefCell<Printer>>,
     pc: u16,
     sp: u16,
-    output: String,
-    printer: String,
-    debug_mode: bool,
+    flags: u8,
+    inst_pc: u16,
+    inst_opcode: u8,
+    halted: bool,
+    device_write: Option<u16>,
+    breakpoints: Vec<Breakpoint>,
+    watchpoints: Vec<Watchpoint>,
+    watch_hit: Option<WatchHit>,
+    protected: Vec<Region>,
+    resume_from: Option<u16>,
+    tracer: Option<Tracer>,
+    profile: Option<Profile>,
+    observers: Vec<Box<dyn Observer>>,
+    history: History,
+    undo: Option<Delta>,
+    steps: u64,
+    max_steps: Option<u64>,
+    max_time: Option<Duration>,
+    deadline: Option<Instant>,
 }
 
 macro_rules! simple_instr_imm {
-    ($fnname:ident, $oper:tt) => {
-        fn $fnname(&mut self, r: u8, data: u16) {
-            let val = self.read_reg(r);
-            self.write_reg(r, val $oper data);
+    ($fnname:ident, $alu:ident) => {
+        fn $fnname(&mut self, r: u8, data: u16) -> Result<()> {
+            let val = self.read_reg(r)?;
+            let res = self.$alu(reg_width(r), val, data)?;
+            self.write_reg(r, res)?;
+            Ok(())
         }
     };
 }
 
 macro_rules! simple_instr_addr {
-    ($fnname:ident, $oper:tt) => {
-        fn $fnname(&mut self, r: u8, addr: u16) {
-            let data = self.read_mem(addr, reg_width(r));
-            let val = self.read_reg(r);
-            self.write_reg(r, val $oper data);
+    ($fnname:ident, $alu:ident) => {
+        fn $fnname(&mut self, r: u8, addr: u16) -> Result<()> {
+            let data = self.read_mem(addr, reg_width(r))?;
+            let val = self.read_reg(r)?;
+            let res = self.$alu(reg_width(r), val, data)?;
+            self.write_reg(r, res)?;
+            Ok(())
         }
     };
 }
 
 macro_rules! simple_instr_reg {
-    ($fnname:ident, $oper:tt) => {
-        fn $fnname(&mut self, dest: u8, src: u8) {
-            let data1 = self.read_reg(src);
-            let data2 = self.read_reg(dest);
-            self.write_reg(dest, data1 $oper data2);
+    ($fnname:ident, $alu:ident) => {
+        fn $fnname(&mut self, dest: u8, src: u8) -> Result<()> {
+            let data = self.read_reg(src)?;
+            let val = self.read_reg(dest)?;
+            let res = self.$alu(reg_width(dest), val, data)?;
+            self.write_reg(dest, res)?;
+            Ok(())
+        }
+    };
+}
+
+macro_rules! flag_jump {
+    ($fnname:ident, $flag:expr, $set:expr) => {
+        fn $fnname(&mut self, pc: u16) -> Result<()> {
+            if self.flag($flag) == $set {
+                self.pc = pc;
+            }
+            Ok(())
         }
     };
 }
 
+impl Default for VM {
+    fn default() -> VM {
+        VM::new()
+    }
+}
+
 impl VM {
     pub fn new() -> VM {
+        let printer = Rc::new(RefCell::new(Printer::new()));
+        let mut bus = Bus::new(NUM_OF_MEMORY_CELLS);
+        bus.attach(Box::new(printer.clone()));
+        bus.attach(Box::new(Timer::new()));
+
         VM {
             registers: vec![0; NUM_OF_REGISTERS],
-            memory: vec![0; NUM_OF_MEMORY_CELLS],
+            bus,
+            printer,
             pc: 0,
             sp: 0,
-            output: String::with_capacity(20),
-            printer: String::with_capacity(20),
-            debug_mode: false,
+            flags: 0,
+            inst_pc: 0,
+            inst_opcode: 0,
+            halted: false,
+            device_write: None,
+            breakpoints: Vec::new(),
+            watchpoints: Vec::new(),
+            watch_hit: None,
+            protected: Vec::new(),
+            resume_from: None,
+            tracer: None,
+            profile: None,
+            observers: Vec::new(),
+            history: History::new(DEFAULT_HISTORY_LIMIT),
+            undo: None,
+            steps: 0,
+            max_steps: None,
+            max_time: None,
+            deadline: None,
         }
     }
 
+    /// Load code into memory and reset the machine. Banking is enabled with
+    /// enough banks if any section is in a bank other than 0.
     pub fn install_code(&mut self, code: &[CodeSection]) {
+        if let Some(max) = code.iter().map(|s| s.bank).filter(|b| *b > 0).max() {
+            self.enable_banking(usize::from(max) + 1);
+        }
+
         for section in code {
             let pc = section.org;
 
             for (i, b) in section.code.iter().enumerate() {
                 let loc = i as u16 + pc;
 
-                self.memory[loc as usize] = b.to_owned();
+                self.bus.poke_bank(section.bank, loc, b.to_owned());
             }
         }
 
@@ -102,207 +235,638 @@ impl VM {
     }
 
     pub fn reset(&mut self) {
-        self.pc = ((u16::from(self.memory[0xFFFE])) << 8) | u16::from(self.memory[0xFFFF]);
+        self.history.clear();
+        self.halted = false;
+        if let Some(mmu) = self.bus.mmu_mut() {
+            mmu.select(0);
+        }
+        self.pc = ((u16::from(self.bus.peek(RESET_VECTOR))) << 8)
+            | u16::from(self.bus.peek(RESET_VECTOR + 1));
     }
 
+    /// Printer output collected in memory. Empty once `set_output` has been
+    /// used.
     pub fn output(&self) -> String {
-        self.output.clone()
+        self.printer.borrow().output()
+    }
+
+    /// Write printer output to `out` as it's printed instead of collecting
+    /// it in memory.
+    pub fn set_output(&mut self, out: Box<dyn Write>) {
+        self.printer.borrow_mut().set_sink(out);
+    }
+
+    /// Attach a memory mapped device. Devices attached later take precedence
+    /// over earlier devices with overlapping addresses, including the printer.
+    pub fn attach_device(&mut self, device: Box<dyn Device>) {
+        self.bus.attach(device);
+    }
+
+    /// Stop before executing the instruction at `addr`. With a condition the
+    /// breakpoint only stops when it holds. Replaces any existing breakpoint
+    /// at the same address.
+    pub fn add_breakpoint(&mut self, addr: u16, condition: Option<Condition>) {
+        self.remove_breakpoint(addr);
+        self.breakpoints.push(Breakpoint { addr, condition });
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: u16) -> bool {
+        let len = self.breakpoints.len();
+        self.breakpoints.retain(|bp| bp.addr != addr);
+        self.breakpoints.len() != len
+    }
+
+    pub fn breakpoints(&self) -> &[Breakpoint] {
+        &self.breakpoints
+    }
+
+    /// Stop after any instruction that accesses memory in `range`.
+    pub fn add_watchpoint(&mut self, range: RangeInclusive<u16>, kind: WatchKind) {
+        self.watchpoints.push(Watchpoint { range, kind });
+    }
+
+    pub fn remove_watchpoint(&mut self, index: usize) -> Option<Watchpoint> {
+        if index < self.watchpoints.len() {
+            Some(self.watchpoints.remove(index))
+        } else {
+            None
+        }
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    /// Add a memory management unit with `count` banks switched through the
+    /// bank window, or add banks to the existing one. See `devices::Mmu`.
+    pub fn enable_banking(&mut self, count: usize) {
+        self.bus.enable_banking(count);
+    }
+
+    /// The selected memory bank, None without banking.
+    pub fn bank(&self) -> Option<u8> {
+        self.bus.mmu().map(|mmu| mmu.selected())
+    }
+
+    /// Select the memory bank seen in the bank window. Does nothing without
+    /// banking.
+    pub fn select_bank(&mut self, bank: u8) {
+        if let Some(mmu) = self.bus.mmu_mut() {
+            mmu.select(bank);
+        }
+    }
+
+    /// Number of memory banks, 0 without banking.
+    pub fn bank_count(&self) -> usize {
+        self.bus.mmu().map_or(0, |mmu| mmu.count())
     }
 
-    fn fetch_byte(&mut self) -> u8 {
-        let b = self.memory[self.pc as usize];
-        self.pc += 1;
-        b
+    /// Protect a region of memory. See `Region` for what's checked.
+    pub fn protect(&mut self, region: Region) {
+        self.protected.push(region);
     }
 
-    fn fetch_u16(&mut self) -> u16 {
-        let b1 = u16::from(self.fetch_byte());
-        let b2 = u16::from(self.fetch_byte());
-        (b1 << 8) | b2
+    pub fn protected_regions(&self) -> &[Region] {
+        &self.protected
     }
 
-    fn print_memory(&self) {
-        print!("Memory   00 01 02 03 04 05 06 07 08 09 0A 0B 0C 0D 0E 0F  10 11 12 13 14 15 16 17 18 19 1A 1B 1C 1D 1E 1F\n\n");
-        let mut i = 0;
+    /// Write a line for every executed instruction to `out`. See `Tracer` for
+    /// the format.
+    pub fn set_trace(&mut self, out: Box<dyn Write>) {
+        self.tracer = Some(Tracer::new(out));
+    }
+
+    /// Stop tracing and flush the trace output.
+    pub fn finish_trace(&mut self) -> io::Result<()> {
+        match self.tracer.take() {
+            Some(tracer) => tracer.finish(),
+            None => Ok(()),
+        }
+    }
+
+    /// Start counting executed instructions. Any previous counts are
+    /// discarded.
+    pub fn enable_profile(&mut self) {
+        self.profile = Some(Profile::new());
+    }
+
+    pub fn profile(&self) -> Option<&Profile> {
+        self.profile.as_ref()
+    }
+
+    /// Add an observer that's called as the machine executes. Without any
+    /// observers the machine doesn't do any extra work.
+    pub fn add_observer(&mut self, observer: Box<dyn Observer>) {
+        self.observers.push(observer);
+    }
+
+    // Observers are moved out while they're called so they can see the machine
+    fn notify(&mut self, event: impl Fn(&mut dyn Observer, &VM)) {
+        if self.observers.is_empty() {
+            return;
+        }
+
+        let mut observers = std::mem::take(&mut self.observers);
+        for observer in &mut observers {
+            event(observer.as_mut(), self);
+        }
+        self.observers = observers;
+    }
 
-        while i < 256 {
-            print!("{:02X}", i);
-            print!("       ");
+    fn trace(&mut self, pc: u16, inst: &dyn fmt::Display) {
+        if let Some(tracer) = &mut self.tracer {
+            tracer.record(pc, inst, &self.registers, self.sp, self.flags);
+        }
+    }
 
-            for j in 0..16 {
-                print!("{:02X}", self.memory[i + j]);
-                print!(" ");
+    // Decode the instruction at addr without triggering device reads
+    fn peek_instruction(&self, addr: u16) -> Option<Instruction> {
+        let bytes: Vec<u8> = (0..4)
+            .map(|i| self.bus.peek(addr.wrapping_add(i)))
+            .collect();
+        Instruction::decode(&bytes)
+    }
+
+    /// Stop with `VmError::StepLimit` once this many instructions have been
+    /// executed.
+    pub fn set_max_steps(&mut self, max: Option<u64>) {
+        self.max_steps = max;
+    }
+
+    /// Stop with `VmError::TimeLimit` once this much wall time has passed
+    /// since the first instruction executed after the limit was set.
+    pub fn set_max_time(&mut self, max: Option<Duration>) {
+        self.max_time = max;
+        self.deadline = None;
+    }
+
+    /// Number of instructions executed since the VM was created.
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    fn check_limits(&mut self) -> Result<()> {
+        if let Some(max) = self.max_steps
+            && self.steps >= max
+        {
+            return Err(VmError::StepLimit(max, self.machine_state()));
+        }
+
+        if let Some(max) = self.max_time {
+            match self.deadline {
+                None => self.deadline = Some(Instant::now() + max),
+                // Checking the clock on every instruction is too slow
+                Some(deadline) if self.steps.is_multiple_of(1024) && Instant::now() >= deadline => {
+                    return Err(VmError::TimeLimit(max, self.machine_state()));
+                }
+                Some(_) => {}
             }
-            print!(" ");
+        }
+        Ok(())
+    }
+
+    /// Set how many instructions can be undone with `step_back`. Recording
+    /// is on by default, a limit of 0 turns it off.
+    pub fn set_history_limit(&mut self, limit: usize) {
+        self.history.set_limit(limit);
+    }
 
-            for j in 16..32 {
-                print!("{:02X}", self.memory[i + j]);
-                print!(" ");
+    /// Number of instructions that can currently be undone.
+    pub fn history_len(&self) -> usize {
+        self.history.len()
+    }
+
+    /// Undo the last executed instruction, returning false if there's no
+    /// history left. Registers, SP, PC, flags, RAM and the selected bank are
+    /// restored but device state such as printer output is not.
+    pub fn step_back(&mut self) -> bool {
+        let delta = match self.history.pop() {
+            Some(delta) => delta,
+            None => return false,
+        };
+
+        // The bank is restored first so unbanked entries go to the right bank
+        if let Some(bank) = delta.bank {
+            self.select_bank(bank);
+        }
+        for (bank, addr, data) in delta.memory.into_iter().rev() {
+            match bank {
+                Some(bank) => self.bus.poke_bank(bank, addr, data),
+                None => self.bus.poke(addr, data),
             }
-            println!();
-            i += 32
         }
+        self.registers = delta.registers;
+        self.pc = delta.pc;
+        self.sp = delta.sp;
+        self.flags = delta.flags;
+        self.halted = delta.halted;
+
+        // Don't stop on a breakpoint at this address when stepping forward again
+        self.resume_from = Some(self.pc);
+        true
     }
 
-    fn print_registers(&self) {
-        println!("0    1    2    3    4    5    6    7    8    9");
-        for reg in &self.registers {
-            print!("0x{:02X} ", reg);
+    /// Step back until reaching a breakpoint or running out of history.
+    /// Returns the breakpoint address if one was hit.
+    pub fn reverse_continue(&mut self) -> Result<Option<u16>> {
+        while self.step_back() {
+            if self.breakpoint_hit(self.pc)? {
+                return Ok(Some(self.pc));
+            }
         }
-        println!("\nPC: 0x{:02X} | SP: 0x{:02X}", self.pc, self.sp);
+        Ok(None)
+    }
+
+    fn begin_undo(&mut self) {
+        self.undo = if self.history.enabled() {
+            Some(Delta {
+                pc: self.pc,
+                sp: self.sp,
+                flags: self.flags,
+                halted: self.halted,
+                registers: self.registers.clone(),
+                bank: self.bank(),
+                memory: Vec::new(),
+            })
+        } else {
+            None
+        };
+    }
+
+    fn commit_undo(&mut self) {
+        if let Some(delta) = self.undo.take() {
+            self.history.push(delta);
+        }
+    }
+
+    fn breakpoint_hit(&self, addr: u16) -> Result<bool> {
+        for bp in self.breakpoints.iter().filter(|bp| bp.addr == addr) {
+            match bp.condition {
+                Some(cond) => {
+                    if cond.matches(self.read_reg(cond.reg)?) {
+                        return Ok(true);
+                    }
+                }
+                None => return Ok(true),
+            }
+        }
+        Ok(false)
+    }
+
+    fn fetch_byte(&mut self) -> Result<u8> {
+        self.check_access(self.pc, Access::Execute)?;
+        let b = self.bus.read(self.pc);
+        self.pc = match self.pc.checked_add(1) {
+            Some(pc) => pc,
+            None => return Err(VmError::PcOverflow(self.machine_state())),
+        };
+        Ok(b)
     }
 
-    pub fn run(&mut self) -> Result<(), &str> {
+    fn fetch_u16(&mut self) -> Result<u16> {
+        let b1 = u16::from(self.fetch_byte()?);
+        let b2 = u16::from(self.fetch_byte()?);
+        Ok((b1 << 8) | b2)
+    }
+
+    fn machine_state(&self) -> MachineState {
+        MachineState {
+            pc: self.inst_pc,
+            opcode: self.inst_opcode,
+            sp: self.sp,
+            flags: self.flags,
+            registers: self.registers.clone(),
+        }
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    /// Move execution to `addr`. A breakpoint at `addr` will stop the next
+    /// step.
+    pub fn set_pc(&mut self, addr: u16) {
+        self.pc = addr;
+        self.inst_pc = addr;
+        self.resume_from = None;
+    }
+
+    pub fn sp(&self) -> u16 {
+        self.sp
+    }
+
+    pub fn set_sp(&mut self, sp: u16) {
+        self.sp = sp;
+    }
+
+    pub fn flags(&self) -> u8 {
+        self.flags
+    }
+
+    pub fn set_flags(&mut self, flags: u8) {
+        self.flags = flags;
+    }
+
+    /// The single width registers 0 - 9.
+    pub fn registers(&self) -> &[u8] {
+        &self.registers
+    }
+
+    /// Read a single or double width register.
+    pub fn register(&self, r: u8) -> Result<u16> {
+        self.read_reg(r)
+    }
+
+    /// Write a single or double width register. Single width registers only
+    /// keep the low byte.
+    pub fn set_register(&mut self, r: u8, data: u16) -> Result<()> {
+        self.check_reg(r)?;
+        self.store_reg(r, data);
+        Ok(())
+    }
+
+    /// Read main memory without going through any devices.
+    pub fn peek(&self, addr: u16) -> u8 {
+        self.bus.peek(addr)
+    }
+
+    /// Write main memory without going through any devices.
+    pub fn poke(&mut self, addr: u16, data: u8) {
+        self.bus.poke(addr, data);
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    /// Execute instructions until the machine halts. DEBUG instructions,
+    /// breakpoints and watchpoints don't stop execution, use a `Debugger` to
+    /// pause on them.
+    pub fn run(&mut self) -> Result<()> {
+        while self.resume()? != StepOutcome::Halted {}
+        Ok(())
+    }
+
+    /// Execute instructions until the machine halts or reaches a DEBUG
+    /// instruction, breakpoint or watchpoint. Returns the outcome of the
+    /// step that stopped execution.
+    pub fn resume(&mut self) -> Result<StepOutcome> {
+        loop {
+            match self.step()? {
+                StepOutcome::Continue | StepOutcome::DeviceWrite(_) => {}
+                outcome => return Ok(outcome),
+            }
+        }
+    }
+    /// Execute exactly one instruction. Once the machine has halted, stepping
+    /// does nothing until it's reset.
+    pub fn step(&mut self) -> Result<StepOutcome> {
+        if self.halted {
+            return Ok(StepOutcome::Halted);
+        }
+
+        self.inst_pc = self.pc;
+        self.inst_opcode = self.bus.peek(self.pc);
+        self.check_limits()?;
+
+        self.device_write = None;
+        self.watch_hit = None;
+        self.begin_undo();
+
+        let irq = self.flag(FLAG_INTERRUPT) && self.bus.interrupt_pending();
+        if irq {
+            let res = self.enter_interrupt();
+            self.trace(self.inst_pc, &"IRQ");
+            res?;
+            self.inst_pc = self.pc;
+        }
+
+        // A breakpoint that was just reported is skipped once so execution
+        // can resume past it.
+        if self.resume_from.take() != Some(self.pc) && self.breakpoint_hit(self.pc)? {
+            if irq {
+                self.commit_undo();
+            }
+            self.resume_from = Some(self.pc);
+            return Ok(StepOutcome::Breakpoint(self.pc));
+        }
+
+        let pc = self.inst_pc;
+        let bank = self.bank().unwrap_or(0);
+        self.notify(|o, vm| o.before_instruction(vm, pc));
+
+        self.inst_opcode = self.fetch_byte()?;
+        let opcode = opc::from_u8(self.inst_opcode)
+            .ok_or_else(|| VmError::UnknownOpcode(self.machine_state()))?;
+        let traced = match self.tracer {
+            Some(_) => self.peek_instruction(self.inst_pc),
+            None => None,
+        };
+        // An instruction that faults is still traced, with the writes it made
+        let res = self.execute(opcode);
+        if let Some(inst) = traced {
+            self.trace(self.inst_pc, &inst);
+        }
+        let mut outcome = res?;
+
+        self.bus.tick();
+
+        if let Some(profile) = &mut self.profile {
+            profile.record(bank, self.inst_pc, opcode);
+        }
+        self.notify(|o, vm| o.after_instruction(vm, pc, opcode));
+        if outcome == StepOutcome::Halted {
+            self.notify(|o, vm| o.on_halt(vm));
+        }
+        self.commit_undo();
+        self.steps += 1;
+
+        // A watchpoint is reported ahead of a HALT or DEBUG in the same step,
+        // such as an interrupt pushing to a watched stack before a handler
+        // that halts. A halted machine reports Halted on the next step.
+        if let Some(hit) = self.watch_hit {
+            outcome = StepOutcome::Watchpoint(hit);
+        } else if outcome == StepOutcome::Continue
+            && let Some(addr) = self.device_write
+        {
+            outcome = StepOutcome::DeviceWrite(addr);
+        }
+        Ok(outcome)
+    }
+
+    // Execute the instruction after its opcode
+    fn execute(&mut self, opcode: opc) -> Result<StepOutcome> {
         macro_rules! instruction {
             ($inst:ident) => {{
-                self.$inst();
+                self.$inst()?;
             }};
 
             ($inst:ident, u8) => {{
-                let arg1 = self.fetch_byte();
-                self.$inst(arg1);
+                let arg1 = self.fetch_byte()?;
+                self.$inst(arg1)?;
             }};
 
             ($inst:ident, u16) => {{
-                let arg1 = self.fetch_u16();
-                self.$inst(arg1);
+                let arg1 = self.fetch_u16()?;
+                self.$inst(arg1)?;
             }};
 
             ($inst:ident, u8, u8) => {{
-                let arg1 = self.fetch_byte();
-                let arg2 = self.fetch_byte();
-                self.$inst(arg1, arg2);
+                let arg1 = self.fetch_byte()?;
+                let arg2 = self.fetch_byte()?;
+                self.$inst(arg1, arg2)?;
             }};
 
             ($inst:ident, u8, u16) => {{
-                let arg1 = self.fetch_byte();
-                let arg2 = self.fetch_u16();
-                self.$inst(arg1, arg2);
+                let arg1 = self.fetch_byte()?;
+                let arg2 = self.fetch_u16()?;
+                self.$inst(arg1, arg2)?;
             }};
         }
 
-        let mut debug_disabled = false;
+        let mut outcome = StepOutcome::Continue;
+        match opcode {
+            opc::NOOP => {}
 
-        loop {
-            let opcode = opc::from(self.fetch_byte());
+            opc::LOADI => instruction!(inst_loadi, u8, u16),
+            opc::LOADA => instruction!(inst_loada, u8, u16),
+            opc::LOADR => instruction!(inst_loadr, u8, u8),
 
-            if self.debug_mode {
-                println!("Breakpoint hit at 0x{:02X}", self.pc - 1);
+            opc::STRA => instruction!(inst_stra, u8, u16),
+            opc::STRR => instruction!(inst_strr, u8, u8),
 
-                loop {
-                    print!("Debug> ");
-                    io::Write::flush(&mut io::stdout()).expect("flush failed!");
+            opc::XFER => instruction!(inst_xfer, u8, u8),
 
-                    let mut input = String::new();
-                    io::stdin().read_line(&mut input).unwrap_or_default();
-                    let parts: Vec<&str> = input.split_whitespace().collect();
-                    if parts.is_empty() {
-                        continue;
-                    }
+            opc::ADDI => instruction!(inst_addi, u8, u16),
+            opc::ADDA => instruction!(inst_adda, u8, u16),
+            opc::ADDR => instruction!(inst_addr, u8, u8),
 
-                    match parts[0] {
-                        "step" => break,
-                        "memory" | "mem" => {
-                            if parts.len() == 1 {
-                                self.print_memory();
-                                continue;
-                            }
-
-                            let address = parts[1].parse::<u16>().unwrap_or_default();
-                            println!(
-                                "[{:02X}] = 0x{:02X}",
-                                address, self.memory[address as usize]
-                            );
-                        }
-                        "continue" | "con" => {
-                            self.debug_mode = false;
-                            break;
-                        }
-                        "disable" | "dis" => debug_disabled = true,
-                        "enable" | "en" => debug_disabled = false,
-                        "next" => println!("Next Instruction: {:?}", opcode),
-                        "registers" | "reg" => self.print_registers(),
-                        "printer" | "print" => println!("{}", self.printer),
-                        "exit" | "quit" => process::exit(0),
-                        _ => println!("Unknown command `{}`", parts[0]),
-                    }
-                }
-            }
-
-            match opcode {
-                opc::LOADI => instruction!(inst_loadi, u8, u16),
-                opc::LOADA => instruction!(inst_loada, u8, u16),
-                opc::LOADR => instruction!(inst_loadr, u8, u8),
+            opc::SUBI => instruction!(inst_subi, u8, u16),
+            opc::SUBA => instruction!(inst_suba, u8, u16),
+            opc::SUBR => instruction!(inst_subr, u8, u8),
 
-                opc::STRA => instruction!(inst_stra, u8, u16),
-                opc::STRR => instruction!(inst_strr, u8, u8),
+            opc::CMPI => instruction!(inst_cmpi, u8, u16),
+            opc::CMPA => instruction!(inst_cmpa, u8, u16),
+            opc::CMPR => instruction!(inst_cmpr, u8, u8),
 
-                opc::XFER => instruction!(inst_xfer, u8, u8),
+            opc::MULI => instruction!(inst_muli, u8, u16),
+            opc::MULA => instruction!(inst_mula, u8, u16),
+            opc::MULR => instruction!(inst_mulr, u8, u8),
 
-                opc::ADDI => instruction!(inst_addi, u8, u16),
-                opc::ADDA => instruction!(inst_adda, u8, u16),
-                opc::ADDR => instruction!(inst_addr, u8, u8),
+            opc::DIVI => instruction!(inst_divi, u8, u16),
+            opc::DIVA => instruction!(inst_diva, u8, u16),
+            opc::DIVR => instruction!(inst_divr, u8, u8),
 
-                opc::ORI => instruction!(inst_ori, u8, u16),
-                opc::ORA => instruction!(inst_ora, u8, u16),
-                opc::ORR => instruction!(inst_orr, u8, u8),
+            opc::MODI => instruction!(inst_modi, u8, u16),
+            opc::MODA => instruction!(inst_moda, u8, u16),
+            opc::MODR => instruction!(inst_modr, u8, u8),
 
-                opc::ANDI => instruction!(inst_andi, u8, u16),
-                opc::ANDA => instruction!(inst_anda, u8, u16),
-                opc::ANDR => instruction!(inst_andr, u8, u8),
+            opc::SHLI => instruction!(inst_shli, u8, u16),
+            opc::SHLA => instruction!(inst_shla, u8, u16),
+            opc::SHLR => instruction!(inst_shlr, u8, u8),
 
-                opc::XORI => instruction!(inst_xori, u8, u16),
-                opc::XORA => instruction!(inst_xora, u8, u16),
-                opc::XORR => instruction!(inst_xorr, u8, u8),
+            opc::SHRI => instruction!(inst_shri, u8, u16),
+            opc::SHRA => instruction!(inst_shra, u8, u16),
+            opc::SHRR => instruction!(inst_shrr, u8, u8),
 
-                opc::ROTR => instruction!(inst_rotr, u8, u8),
-                opc::ROTL => instruction!(inst_rotl, u8, u8),
+            opc::ASRI => instruction!(inst_asri, u8, u16),
+            opc::ASRA => instruction!(inst_asra, u8, u16),
+            opc::ASRR => instruction!(inst_asrr, u8, u8),
 
-                opc::JMP => instruction!(inst_jmp, u8, u16),
-                opc::JMPA => instruction!(inst_jmpa, u16),
+            opc::ORI => instruction!(inst_ori, u8, u16),
+            opc::ORA => instruction!(inst_ora, u8, u16),
+            opc::ORR => instruction!(inst_orr, u8, u8),
 
-                opc::HALT => break,
+            opc::ANDI => instruction!(inst_andi, u8, u16),
+            opc::ANDA => instruction!(inst_anda, u8, u16),
+            opc::ANDR => instruction!(inst_andr, u8, u8),
 
-                opc::LDSPI => instruction!(inst_ldspi, u16),
-                opc::LDSPA => instruction!(inst_ldspa, u16),
-                opc::LDSPR => instruction!(inst_ldspr, u8),
+            opc::XORI => instruction!(inst_xori, u8, u16),
+            opc::XORA => instruction!(inst_xora, u8, u16),
+            opc::XORR => instruction!(inst_xorr, u8, u8),
 
-                opc::PUSH => instruction!(inst_push, u8),
-                opc::POP => instruction!(inst_pop, u8),
+            opc::ROTR => instruction!(inst_rotr, u8, u8),
+            opc::ROTL => instruction!(inst_rotl, u8, u8),
 
-                opc::CALLA => instruction!(inst_calla, u16),
-                opc::CALLR => instruction!(inst_callr, u8),
+            opc::JMP => instruction!(inst_jmp, u8, u16),
+            opc::JMPA => instruction!(inst_jmpa, u16),
 
-                opc::RTN => instruction!(inst_rtn),
+            opc::JZ => instruction!(inst_jz, u16),
+            opc::JNZ => instruction!(inst_jnz, u16),
+            opc::JC => instruction!(inst_jc, u16),
+            opc::JNC => instruction!(inst_jnc, u16),
+            opc::JN => instruction!(inst_jn, u16),
+            opc::JV => instruction!(inst_jv, u16),
 
-                opc::DEBUG => {
-                    if !debug_disabled {
-                        self.debug_mode = true
-                    }
-                }
-                _ => return Err("Unknown opcode encountered"),
+            opc::HALT => {
+                self.halted = true;
+                outcome = StepOutcome::Halted;
             }
 
-            if self.memory[PRINTER_ADDR] > 0 {
-                self.printer.push(self.memory[PRINTER_ADDR] as char);
-                self.memory[PRINTER_ADDR] = 0
-            }
+            opc::LDSPI => instruction!(inst_ldspi, u16),
+            opc::LDSPA => instruction!(inst_ldspa, u16),
+            opc::LDSPR => instruction!(inst_ldspr, u8),
+
+            opc::PUSH => instruction!(inst_push, u8),
+            opc::POP => instruction!(inst_pop, u8),
+
+            opc::CALLA => instruction!(inst_calla, u16),
+            opc::CALLR => instruction!(inst_callr, u8),
+
+            opc::RTN => instruction!(inst_rtn),
+
+            opc::SEI => instruction!(inst_sei),
+            opc::CLI => instruction!(inst_cli),
+            opc::RTI => instruction!(inst_rti),
+
+            opc::DEBUG => outcome = StepOutcome::Debug,
+            opc::End => return Err(VmError::UnknownOpcode(self.machine_state())),
         }
+        Ok(outcome)
+    }
 
-        self.output += self.printer.as_str();
+    // Interrupts
+    // The return address and flags are pushed to the stack and further
+    // interrupts are disabled until the handler returns with RTI.
+    fn enter_interrupt(&mut self) -> Result<()> {
+        let pc = self.pc;
+        self.push(2, pc)?;
+        self.push(1, u16::from(self.flags))?;
+        self.set_flag(FLAG_INTERRUPT, false);
+        self.pc = self.read_mem(IRQ_VECTOR, 2)?;
         Ok(())
     }
 
     // Register manipulation
-    fn read_reg(&self, r: u8) -> u16 {
+    fn check_reg(&self, r: u8) -> Result<()> {
+        if r as usize >= NUM_OF_REGISTERS && !is_double_reg(r) {
+            Err(VmError::InvalidRegister(r, self.machine_state()))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn read_reg(&self, r: u8) -> Result<u16> {
+        self.check_reg(r)?;
+
         if is_double_reg(r) {
-            self.read_double_reg(r)
+            Ok(self.read_double_reg(r))
         } else {
-            u16::from(self.read_single_reg(r))
+            Ok(u16::from(self.read_single_reg(r)))
         }
     }
 
-    fn write_reg(&mut self, r: u8, data: u16) {
+    fn write_reg(&mut self, r: u8, data: u16) -> Result<()> {
+        self.check_reg(r)?;
+        self.store_reg(r, data);
+
+        for observer in &mut self.observers {
+            observer.on_register_write(r, data & width_mask(reg_width(r)));
+        }
+        Ok(())
+    }
+
+    fn store_reg(&mut self, r: u8, data: u16) {
         if is_double_reg(r) {
             self.write_double_reg(r, data);
         } else {
@@ -348,207 +912,1256 @@ impl VM {
         }
     }
 
+    // Flags
+    fn flag(&self, f: u8) -> bool {
+        self.flags & f > 0
+    }
+
+    fn set_flag(&mut self, f: u8, set: bool) {
+        if set {
+            self.flags |= f;
+        } else {
+            self.flags &= !f;
+        }
+    }
+
+    fn set_result_flags(&mut self, width: u8, res: u16) {
+        self.set_flag(FLAG_ZERO, res & width_mask(width) == 0);
+        self.set_flag(FLAG_NEGATIVE, res & width_sign(width) > 0);
+    }
+
+    // ALU
+    fn alu_add(&mut self, width: u8, a: u16, b: u16) -> Result<u16> {
+        let mask = width_mask(width);
+        let (a, b) = (a & mask, b & mask);
+        let sum = u32::from(a) + u32::from(b);
+        let res = sum as u16 & mask;
+
+        self.set_flag(FLAG_CARRY, sum > u32::from(mask));
+        self.set_flag(FLAG_OVERFLOW, (a ^ res) & (b ^ res) & width_sign(width) > 0);
+        self.set_result_flags(width, res);
+        Ok(res)
+    }
+
+    // The carry flag is set when a borrow was needed
+    fn alu_sub(&mut self, width: u8, a: u16, b: u16) -> Result<u16> {
+        let mask = width_mask(width);
+        let (a, b) = (a & mask, b & mask);
+        let res = a.wrapping_sub(b) & mask;
+
+        self.set_flag(FLAG_CARRY, a < b);
+        self.set_flag(FLAG_OVERFLOW, (a ^ b) & (a ^ res) & width_sign(width) > 0);
+        self.set_result_flags(width, res);
+        Ok(res)
+    }
+
+    // Compare sets flags the same as subtract but leaves the register untouched
+    fn alu_cmp(&mut self, width: u8, a: u16, b: u16) -> Result<()> {
+        self.alu_sub(width, a, b)?;
+        Ok(())
+    }
+
+    // The carry and overflow flags are set when the product didn't fit
+    fn alu_mul(&mut self, width: u8, a: u16, b: u16) -> Result<u16> {
+        let mask = width_mask(width);
+        let prod = u32::from(a & mask) * u32::from(b & mask);
+        let res = prod as u16 & mask;
+
+        self.set_flag(FLAG_CARRY, prod > u32::from(mask));
+        self.set_flag(FLAG_OVERFLOW, prod > u32::from(mask));
+        self.set_result_flags(width, res);
+        Ok(res)
+    }
+
+    fn alu_div(&mut self, width: u8, a: u16, b: u16) -> Result<u16> {
+        let mask = width_mask(width);
+        if b & mask == 0 {
+            return Err(VmError::DivideByZero(self.machine_state()));
+        }
+        self.alu_logic(width, (a & mask) / (b & mask))
+    }
+
+    fn alu_mod(&mut self, width: u8, a: u16, b: u16) -> Result<u16> {
+        let mask = width_mask(width);
+        if b & mask == 0 {
+            return Err(VmError::DivideByZero(self.machine_state()));
+        }
+        self.alu_logic(width, (a & mask) % (b & mask))
+    }
+
+    // Shifts set the carry flag to the last bit shifted out
+    fn alu_shl(&mut self, width: u8, a: u16, places: u16) -> Result<u16> {
+        let bits = u32::from(width) * 8;
+        let val = u32::from(a & width_mask(width));
+        let shifted = if u32::from(places) > bits {
+            0
+        } else {
+            val << places
+        };
+
+        let res = self.alu_logic(width, shifted as u16)?;
+        self.set_flag(FLAG_CARRY, places > 0 && shifted & (1 << bits) > 0);
+        Ok(res)
+    }
+
+    fn alu_shr(&mut self, width: u8, a: u16, places: u16) -> Result<u16> {
+        let val = u32::from(a & width_mask(width)) << 1;
+        let shifted = val.checked_shr(u32::from(places)).unwrap_or(0);
+
+        let res = self.alu_logic(width, (shifted >> 1) as u16)?;
+        self.set_flag(FLAG_CARRY, places > 0 && shifted & 1 > 0);
+        Ok(res)
+    }
+
+    fn alu_asr(&mut self, width: u8, a: u16, places: u16) -> Result<u16> {
+        let bits = u32::from(width) * 8;
+        // Move the value to the top of an i32 so the sign bit is extended
+        let val = (i32::from(a & width_mask(width)) << (32 - bits)) >> (31 - bits);
+        let shifted = val >> places.min(31);
+
+        let res = self.alu_logic(width, (shifted >> 1) as u16)?;
+        self.set_flag(FLAG_CARRY, places > 0 && shifted & 1 > 0);
+        Ok(res)
+    }
+
+    fn alu_logic(&mut self, width: u8, res: u16) -> Result<u16> {
+        let res = res & width_mask(width);
+        self.set_flag(FLAG_CARRY, false);
+        self.set_flag(FLAG_OVERFLOW, false);
+        self.set_result_flags(width, res);
+        Ok(res)
+    }
+
+    fn alu_and(&mut self, width: u8, a: u16, b: u16) -> Result<u16> {
+        self.alu_logic(width, a & b)
+    }
+
+    fn alu_or(&mut self, width: u8, a: u16, b: u16) -> Result<u16> {
+        self.alu_logic(width, a | b)
+    }
+
+    fn alu_xor(&mut self, width: u8, a: u16, b: u16) -> Result<u16> {
+        self.alu_logic(width, a ^ b)
+    }
+
     // Memory manipulation
-    fn read_mem(&self, addr: u16, width: u8) -> u16 {
-        if width == 1 {
-            return u16::from(self.memory[addr as usize]);
-        } else if width == 2 {
-            let b1 = u16::from(self.memory[addr as usize]);
-            let b2 = u16::from(self.memory[(addr + 1) as usize]);
-            return (b1 << 8) | b2;
+    fn check_mem(&self, addr: u16, width: u8) -> Result<()> {
+        if usize::from(addr) + usize::from(width) > NUM_OF_MEMORY_CELLS {
+            Err(VmError::MemoryFault(addr, self.machine_state()))
+        } else {
+            Ok(())
         }
+    }
 
-        0
+    fn check_access(&self, addr: u16, access: Access) -> Result<()> {
+        if self.protected.iter().any(|r| r.forbids(addr, access)) {
+            Err(VmError::AccessViolation(addr, access, self.machine_state()))
+        } else {
+            Ok(())
+        }
     }
 
-    fn write_mem(&mut self, addr: u16, width: u8, data: u16) {
+    fn read_mem(&mut self, addr: u16, width: u8) -> Result<u16> {
+        self.check_mem(addr, width)?;
+
         if width == 1 {
-            self.memory[addr as usize] = data as u8;
+            return Ok(u16::from(self.read_bus(addr)));
         } else if width == 2 {
-            self.memory[addr as usize] = (data >> 8) as u8;
-            self.memory[(addr + 1) as usize] = data as u8;
+            let b1 = u16::from(self.read_bus(addr));
+            let b2 = u16::from(self.read_bus(addr + 1));
+            return Ok((b1 << 8) | b2);
         }
+
+        Ok(0)
     }
 
-    fn read_mem_u8(&self, addr: u16) -> u8 {
-        self.read_mem(addr, 1) as u8
+    fn write_mem(&mut self, addr: u16, width: u8, data: u16) -> Result<()> {
+        self.check_mem(addr, width)?;
+        // Nothing is written if any byte is protected
+        for i in 0..u16::from(width) {
+            self.check_access(addr + i, Access::Write)?;
+        }
+
+        if width == 1 {
+            self.write_bus(addr, data as u8);
+        } else if width == 2 {
+            self.write_bus(addr, (data >> 8) as u8);
+            self.write_bus(addr + 1, data as u8);
+        }
+        Ok(())
     }
 
-    fn write_mem_u8(&mut self, addr: u16, data: u8) {
-        self.write_mem(addr, 1, u16::from(data))
+    fn read_bus(&mut self, addr: u16) -> u8 {
+        let data = self.bus.read(addr);
+        self.check_watchpoints(addr, WatchKind::Read, data);
+        for observer in &mut self.observers {
+            observer.on_memory_read(addr, data);
+        }
+        data
     }
 
-    fn read_mem_u16(&self, addr: u16) -> u16 {
-        self.read_mem(addr, 2)
+    fn write_bus(&mut self, addr: u16, data: u8) {
+        let old = self.bus.peek(addr);
+        if self.bus.write(addr, data) {
+            self.device_write = Some(addr);
+        } else if let Some(delta) = &mut self.undo {
+            let bank = match self.bus.mmu() {
+                Some(mmu) if BANK_WINDOW.contains(&addr) => Some(mmu.selected()),
+                _ => None,
+            };
+            delta.memory.push((bank, addr, old));
+        }
+        if let Some(tracer) = &mut self.tracer {
+            tracer.record_write(addr, data);
+        }
+        for observer in &mut self.observers {
+            observer.on_memory_write(addr, data);
+        }
+        self.check_watchpoints(addr, WatchKind::Write, data);
     }
 
-    fn write_mem_u16(&mut self, addr: u16, data: u16) {
-        self.write_mem(addr, 2, data)
+    // Only the first access of an instruction is reported
+    fn check_watchpoints(&mut self, addr: u16, kind: WatchKind, value: u8) {
+        if self.watch_hit.is_none() && self.watchpoints.iter().any(|wp| wp.matches(addr, kind)) {
+            self.watch_hit = Some(WatchHit { addr, kind, value });
+        }
     }
 
     // Instructions
 
     // LOAD
-    fn inst_loadi(&mut self, r: u8, data: u16) {
-        self.write_reg(r, data);
+    fn inst_loadi(&mut self, r: u8, data: u16) -> Result<()> {
+        self.write_reg(r, data)
     }
 
-    fn inst_loada(&mut self, r: u8, addr: u16) {
-        let data = self.read_mem(addr, reg_width(r));
-        self.write_reg(r, data);
+    fn inst_loada(&mut self, r: u8, addr: u16) -> Result<()> {
+        let data = self.read_mem(addr, reg_width(r))?;
+        self.write_reg(r, data)
     }
 
-    fn inst_loadr(&mut self, dest: u8, src: u8) {
-        let addr = self.read_reg(src);
-        self.inst_loada(dest, addr);
+    fn inst_loadr(&mut self, dest: u8, src: u8) -> Result<()> {
+        let addr = self.read_reg(src)?;
+        self.inst_loada(dest, addr)
     }
 
     // STORE
-    fn inst_stra(&mut self, src: u8, addr: u16) {
-        let data = self.read_reg(src);
-        self.write_mem(addr, reg_width(src), data);
+    fn inst_stra(&mut self, src: u8, addr: u16) -> Result<()> {
+        let data = self.read_reg(src)?;
+        self.write_mem(addr, reg_width(src), data)
     }
 
-    fn inst_strr(&mut self, src: u8, dest: u8) {
-        let addr = self.read_reg(src);
-        self.inst_stra(dest, addr);
+    fn inst_strr(&mut self, src: u8, dest: u8) -> Result<()> {
+        let addr = self.read_reg(src)?;
+        self.inst_stra(dest, addr)
     }
 
     // XFER
-    fn inst_xfer(&mut self, dest: u8, src: u8) {
-        let data = self.read_reg(src);
-        self.write_reg(dest, data);
+    fn inst_xfer(&mut self, dest: u8, src: u8) -> Result<()> {
+        let data = self.read_reg(src)?;
+        self.write_reg(dest, data)
     }
 
     // ADD
-    simple_instr_imm!(inst_addi, +);
-    simple_instr_addr!(inst_adda, +);
-    simple_instr_reg!(inst_addr, +);
+    simple_instr_imm!(inst_addi, alu_add);
+    simple_instr_addr!(inst_adda, alu_add);
+    simple_instr_reg!(inst_addr, alu_add);
+
+    // SUB
+    simple_instr_imm!(inst_subi, alu_sub);
+    simple_instr_addr!(inst_suba, alu_sub);
+    simple_instr_reg!(inst_subr, alu_sub);
+
+    // CMP
+    // The register isn't written, so observers and the undo history don't
+    // see a change
+    fn inst_cmpi(&mut self, r: u8, data: u16) -> Result<()> {
+        let val = self.read_reg(r)?;
+        self.alu_cmp(reg_width(r), val, data)
+    }
+
+    fn inst_cmpa(&mut self, r: u8, addr: u16) -> Result<()> {
+        let data = self.read_mem(addr, reg_width(r))?;
+        let val = self.read_reg(r)?;
+        self.alu_cmp(reg_width(r), val, data)
+    }
+
+    fn inst_cmpr(&mut self, dest: u8, src: u8) -> Result<()> {
+        let data = self.read_reg(src)?;
+        let val = self.read_reg(dest)?;
+        self.alu_cmp(reg_width(dest), val, data)
+    }
+
+    // MUL
+    simple_instr_imm!(inst_muli, alu_mul);
+    simple_instr_addr!(inst_mula, alu_mul);
+    simple_instr_reg!(inst_mulr, alu_mul);
+
+    // DIV
+    simple_instr_imm!(inst_divi, alu_div);
+    simple_instr_addr!(inst_diva, alu_div);
+    simple_instr_reg!(inst_divr, alu_div);
+
+    // MOD
+    simple_instr_imm!(inst_modi, alu_mod);
+    simple_instr_addr!(inst_moda, alu_mod);
+    simple_instr_reg!(inst_modr, alu_mod);
+
+    // SHIFT
+    simple_instr_imm!(inst_shli, alu_shl);
+    simple_instr_addr!(inst_shla, alu_shl);
+    simple_instr_reg!(inst_shlr, alu_shl);
+
+    simple_instr_imm!(inst_shri, alu_shr);
+    simple_instr_addr!(inst_shra, alu_shr);
+    simple_instr_reg!(inst_shrr, alu_shr);
+
+    simple_instr_imm!(inst_asri, alu_asr);
+    simple_instr_addr!(inst_asra, alu_asr);
+    simple_instr_reg!(inst_asrr, alu_asr);
 
     // OR
-    simple_instr_imm!(inst_ori, |);
-    simple_instr_addr!(inst_ora, |);
-    simple_instr_reg!(inst_orr, |);
+    simple_instr_imm!(inst_ori, alu_or);
+    simple_instr_addr!(inst_ora, alu_or);
+    simple_instr_reg!(inst_orr, alu_or);
 
     // AND
-    simple_instr_imm!(inst_andi, &);
-    simple_instr_addr!(inst_anda, &);
-    simple_instr_reg!(inst_andr, &);
+    simple_instr_imm!(inst_andi, alu_and);
+    simple_instr_addr!(inst_anda, alu_and);
+    simple_instr_reg!(inst_andr, alu_and);
 
     // XOR
-    simple_instr_imm!(inst_xori, ^);
-    simple_instr_addr!(inst_xora, ^);
-    simple_instr_reg!(inst_xorr, ^);
+    simple_instr_imm!(inst_xori, alu_xor);
+    simple_instr_addr!(inst_xora, alu_xor);
+    simple_instr_reg!(inst_xorr, alu_xor);
 
     // ROTATE
-    fn inst_rotr(&mut self, dest: u8, places: u8) {
+    // The carry flag holds the last bit rotated around.
+    fn inst_rotr(&mut self, dest: u8, places: u8) -> Result<()> {
+        self.check_reg(dest)?;
+
         if is_double_reg(dest) {
             let val = self.read_double_reg(dest);
             let data = val.rotate_right(u32::from(places));
-            self.write_double_reg(dest, data);
+            self.write_reg(dest, data)?;
+            self.alu_logic(2, data)?;
+            self.set_flag(FLAG_CARRY, places > 0 && data & 0x8000 > 0);
         }
+        Ok(())
     }
 
-    fn inst_rotl(&mut self, dest: u8, places: u8) {
+    fn inst_rotl(&mut self, dest: u8, places: u8) -> Result<()> {
+        self.check_reg(dest)?;
+
         if is_double_reg(dest) {
             let val = self.read_double_reg(dest);
             let data = val.rotate_left(u32::from(places));
-            self.write_double_reg(dest, data);
+            self.write_reg(dest, data)?;
+            self.alu_logic(2, data)?;
+            self.set_flag(FLAG_CARRY, places > 0 && data & 0x0001 > 0);
         }
+        Ok(())
     }
 
     // JUMP
-    fn inst_jmp(&mut self, r: u8, pc: u16) {
-        let zero_reg = self.read_reg(0);
-        let check_reg = self.read_reg(r);
+    fn inst_jmp(&mut self, r: u8, pc: u16) -> Result<()> {
+        let zero_reg = self.read_reg(0)?;
+        let check_reg = self.read_reg(r)?;
         if check_reg == zero_reg {
             self.pc = pc;
         }
+        Ok(())
     }
 
-    fn inst_jmpa(&mut self, pc: u16) {
+    fn inst_jmpa(&mut self, pc: u16) -> Result<()> {
         self.pc = pc;
+        Ok(())
     }
 
+    flag_jump!(inst_jz, FLAG_ZERO, true);
+    flag_jump!(inst_jnz, FLAG_ZERO, false);
+    flag_jump!(inst_jc, FLAG_CARRY, true);
+    flag_jump!(inst_jnc, FLAG_CARRY, false);
+    flag_jump!(inst_jn, FLAG_NEGATIVE, true);
+    flag_jump!(inst_jv, FLAG_OVERFLOW, true);
+
     // LOAD SP
-    fn inst_ldspi(&mut self, addr: u16) {
+    fn inst_ldspi(&mut self, addr: u16) -> Result<()> {
         self.sp = addr;
+        Ok(())
     }
 
-    fn inst_ldspa(&mut self, addr: u16) {
-        let sp_val = self.read_mem_u16(addr);
+    fn inst_ldspa(&mut self, addr: u16) -> Result<()> {
+        let sp_val = self.read_mem(addr, 2)?;
         self.sp = sp_val;
+        Ok(())
     }
 
-    fn inst_ldspr(&mut self, r: u8) {
-        let sp_val = self.read_reg(r);
+    fn inst_ldspr(&mut self, r: u8) -> Result<()> {
+        let sp_val = self.read_reg(r)?;
         self.sp = sp_val;
+        Ok(())
     }
 
     // PUSH/POP
-    fn push_u16(&mut self, data: u16) {
-        self.sp -= 2;
-        let sp = self.sp;
-        self.write_mem_u16(sp, data);
+    // The stack grows down, pushing below 0x0000 overflows the stack and
+    // popping past 0xFFFF underflows it.
+    fn push(&mut self, width: u8, data: u16) -> Result<()> {
+        let sp = match self.sp.checked_sub(u16::from(width)) {
+            Some(sp) => sp,
+            None => return Err(VmError::StackOverflow(self.machine_state())),
+        };
+        self.write_mem(sp, width, data)?;
+        self.sp = sp;
+        Ok(())
     }
 
-    fn push_u8(&mut self, data: u8) {
-        self.sp -= 1;
-        let sp = self.sp;
-        self.write_mem_u8(sp, data);
+    fn pop(&mut self, width: u8) -> Result<u16> {
+        let sp = match self.sp.checked_add(u16::from(width)) {
+            Some(sp) => sp,
+            None => return Err(VmError::StackUnderflow(self.machine_state())),
+        };
+        let data = self.read_mem(self.sp, width)?;
+        self.sp = sp;
+        Ok(data)
     }
 
-    fn pop_u16(&mut self) -> u16 {
-        let sp = self.sp;
-        let data = self.read_mem_u16(sp);
-        self.sp += 2;
-        data
+    fn inst_push(&mut self, r: u8) -> Result<()> {
+        let val = self.read_reg(r)?;
+        self.push(reg_width(r), val)
     }
 
-    fn pop_u8(&mut self) -> u8 {
-        let sp = self.sp;
-        let data = self.read_mem_u8(sp);
-        self.sp += 1;
-        data
+    fn inst_pop(&mut self, r: u8) -> Result<()> {
+        let val = self.pop(reg_width(r))?;
+        self.write_reg(r, val)
     }
 
-    fn inst_push(&mut self, r: u8) {
-        if is_double_reg(r) {
-            let val = self.read_double_reg(r);
-            self.push_u16(val);
-        } else {
-            let val = self.read_single_reg(r);
-            self.push_u8(val);
+    // CALL
+    fn inst_calla(&mut self, addr: u16) -> Result<()> {
+        let pc = self.pc;
+        self.push(2, pc)?;
+        self.pc = addr;
+        Ok(())
+    }
+
+    fn inst_callr(&mut self, r: u8) -> Result<()> {
+        let new_pc = self.read_reg(r)?;
+
+        let pc = self.pc;
+        self.push(2, pc)?;
+        self.pc = new_pc;
+        Ok(())
+    }
+
+    fn inst_rtn(&mut self) -> Result<()> {
+        let pc = self.pop(2)?;
+        self.pc = pc;
+        Ok(())
+    }
+
+    // INTERRUPTS
+    fn inst_sei(&mut self) -> Result<()> {
+        self.set_flag(FLAG_INTERRUPT, true);
+        Ok(())
+    }
+
+    fn inst_cli(&mut self) -> Result<()> {
+        self.set_flag(FLAG_INTERRUPT, false);
+        Ok(())
+    }
+
+    fn inst_rti(&mut self) -> Result<()> {
+        self.flags = self.pop(1)? as u8;
+        self.pc = self.pop(2)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::devices::OutputBuffer;
+
+    fn run_code(code: &[&[u8]]) -> VM {
+        let mut vm = VM::new();
+        vm.install_code(&[CodeSection {
+            org: 0,
+            bank: 0,
+            code: code.concat(),
+        }]);
+        vm.run().unwrap();
+        vm
+    }
+
+    #[test]
+    fn test_add_flags() {
+        let vm = run_code(&[
+            &[opc::LOADI as u8, 1, 0x00, 0xFF],
+            &[opc::ADDI as u8, 1, 0x00, 0x01],
+            &[opc::HALT as u8],
+        ]);
+
+        assert_eq!(vm.read_reg(1).unwrap(), 0);
+        assert!(vm.flag(FLAG_ZERO));
+        assert!(vm.flag(FLAG_CARRY));
+        assert!(!vm.flag(FLAG_NEGATIVE));
+        assert!(!vm.flag(FLAG_OVERFLOW));
+    }
+
+    #[test]
+    fn test_add_signed_overflow() {
+        let vm = run_code(&[
+            &[opc::LOADI as u8, 0xA, 0x7F, 0xFF],
+            &[opc::ADDI as u8, 0xA, 0x00, 0x01],
+            &[opc::HALT as u8],
+        ]);
+
+        assert_eq!(vm.read_reg(0xA).unwrap(), 0x8000);
+        assert!(!vm.flag(FLAG_ZERO));
+        assert!(!vm.flag(FLAG_CARRY));
+        assert!(vm.flag(FLAG_NEGATIVE));
+        assert!(vm.flag(FLAG_OVERFLOW));
+    }
+
+    #[test]
+    fn test_logic_clears_carry() {
+        let vm = run_code(&[
+            &[opc::LOADI as u8, 1, 0x00, 0xFF],
+            &[opc::ADDI as u8, 1, 0x00, 0x02],
+            &[opc::ANDI as u8, 1, 0x00, 0x80],
+            &[opc::HALT as u8],
+        ]);
+
+        assert!(vm.flag(FLAG_ZERO));
+        assert!(!vm.flag(FLAG_CARRY));
+    }
+
+    #[test]
+    fn test_flag_jumps() {
+        // Count register 1 down from 3, counting loops in register 2
+        let vm = run_code(&[
+            &[opc::LOADI as u8, 1, 0x00, 0x03],
+            &[opc::ADDI as u8, 2, 0x00, 0x01],
+            &[opc::ADDI as u8, 1, 0x00, 0xFF],
+            &[opc::JNZ as u8, 0x00, 0x04],
+            &[opc::HALT as u8],
+        ]);
+
+        assert_eq!(vm.read_reg(1).unwrap(), 0);
+        assert_eq!(vm.read_reg(2).unwrap(), 3);
+    }
+
+    #[test]
+    fn test_sub_borrow() {
+        let vm = run_code(&[
+            &[opc::LOADI as u8, 1, 0x00, 0x03],
+            &[opc::SUBI as u8, 1, 0x00, 0x05],
+            &[opc::HALT as u8],
+        ]);
+
+        assert_eq!(vm.read_reg(1).unwrap(), 0xFE);
+        assert!(vm.flag(FLAG_CARRY));
+        assert!(vm.flag(FLAG_NEGATIVE));
+    }
+
+    #[test]
+    fn test_cmp_keeps_register() {
+        let vm = run_code(&[
+            &[opc::LOADI as u8, 0xA, 0x12, 0x34],
+            &[opc::CMPI as u8, 0xA, 0x12, 0x34],
+            &[opc::HALT as u8],
+        ]);
+
+        assert_eq!(vm.read_reg(0xA).unwrap(), 0x1234);
+        assert!(vm.flag(FLAG_ZERO));
+        assert!(!vm.flag(FLAG_CARRY));
+    }
+
+    #[test]
+    fn test_mul_div_mod() {
+        let vm = run_code(&[
+            &[opc::LOADI as u8, 0xA, 0x00, 0x07],
+            &[opc::MULI as u8, 0xA, 0x00, 0x06],
+            &[opc::LOADI as u8, 0xB, 0x00, 0x2A],
+            &[opc::DIVI as u8, 0xB, 0x00, 0x05],
+            &[opc::LOADI as u8, 0xC, 0x00, 0x2A],
+            &[opc::MODI as u8, 0xC, 0x00, 0x05],
+            &[opc::HALT as u8],
+        ]);
+
+        assert_eq!(vm.read_reg(0xA).unwrap(), 42);
+        assert_eq!(vm.read_reg(0xB).unwrap(), 8);
+        assert_eq!(vm.read_reg(0xC).unwrap(), 2);
+    }
+
+    fn run_err(code: &[&[u8]]) -> VmError {
+        let mut vm = VM::new();
+        vm.install_code(&[CodeSection {
+            org: 0,
+            bank: 0,
+            code: code.concat(),
+        }]);
+        vm.run().unwrap_err()
+    }
+
+    #[test]
+    fn test_divide_by_zero() {
+        let err = run_err(&[&[opc::LOADI as u8, 1, 0x00, 0x01], &[opc::DIVR as u8, 1, 2]]);
+
+        match err {
+            VmError::DivideByZero(state) => {
+                assert_eq!(state.pc, 4);
+                assert_eq!(state.opcode, opc::DIVR as u8);
+                assert_eq!(state.registers[1], 1);
+            }
+            e => panic!("unexpected error {}", e),
         }
     }
 
-    fn inst_pop(&mut self, r: u8) {
-        if is_double_reg(r) {
-            let val = self.pop_u16();
-            self.write_double_reg(r, val);
-        } else {
-            let val = self.pop_u8();
-            self.write_single_reg(r, val);
+    #[test]
+    fn test_unknown_opcode() {
+        let err = run_err(&[&[0xFF]]);
+        assert!(matches!(err, VmError::UnknownOpcode(_)));
+        assert_eq!(err.state().opcode, 0xFF);
+    }
+
+    #[test]
+    fn test_invalid_register() {
+        let err = run_err(&[&[opc::LOADI as u8, 0x0E, 0x00, 0x01]]);
+        assert!(matches!(err, VmError::InvalidRegister(0x0E, _)));
+    }
+
+    #[test]
+    fn test_memory_fault() {
+        let err = run_err(&[&[opc::LOADA as u8, 0xA, 0xFF, 0xFF]]);
+        assert!(matches!(err, VmError::MemoryFault(0xFFFF, _)));
+    }
+
+    #[test]
+    fn test_stack_overflow() {
+        let err = run_err(&[&[opc::LDSPI as u8, 0x00, 0x01], &[opc::PUSH as u8, 0xA]]);
+        assert!(matches!(err, VmError::StackOverflow(_)));
+    }
+
+    #[test]
+    fn test_stack_underflow() {
+        let err = run_err(&[&[opc::LDSPI as u8, 0xFF, 0xFF], &[opc::RTN as u8]]);
+        assert!(matches!(err, VmError::StackUnderflow(_)));
+    }
+
+    #[test]
+    fn test_shifts() {
+        let vm = run_code(&[
+            &[opc::LOADI as u8, 1, 0x00, 0x81],
+            &[opc::SHLI as u8, 1, 0x00, 0x01],
+            &[opc::LOADI as u8, 2, 0x00, 0x81],
+            &[opc::SHRI as u8, 2, 0x00, 0x01],
+            &[opc::LOADI as u8, 3, 0x00, 0x81],
+            &[opc::ASRI as u8, 3, 0x00, 0x01],
+            &[opc::HALT as u8],
+        ]);
+
+        assert_eq!(vm.read_reg(1).unwrap(), 0x02);
+        assert_eq!(vm.read_reg(2).unwrap(), 0x40);
+        assert_eq!(vm.read_reg(3).unwrap(), 0xC0);
+        assert!(vm.flag(FLAG_CARRY));
+    }
+
+    #[test]
+    fn test_step() {
+        let mut vm = VM::new();
+        vm.install_code(&[CodeSection {
+            org: 0,
+            bank: 0,
+            code: [
+                &[opc::LOADI as u8, 1, 0x00, b'A'][..],
+                &[opc::STRA as u8, 1, 0xFF, 0xFD],
+                &[opc::DEBUG as u8],
+                &[opc::HALT as u8],
+            ]
+            .concat(),
+        }]);
+
+        assert_eq!(vm.step().unwrap(), StepOutcome::Continue);
+        assert_eq!(vm.read_reg(1).unwrap(), u16::from(b'A'));
+        assert_eq!(vm.step().unwrap(), StepOutcome::DeviceWrite(0xFFFD));
+        assert_eq!(vm.step().unwrap(), StepOutcome::Debug);
+        assert_eq!(vm.step().unwrap(), StepOutcome::Halted);
+        assert_eq!(vm.pc, 10);
+
+        // Stepping a halted machine doesn't execute anything
+        assert_eq!(vm.step().unwrap(), StepOutcome::Halted);
+        assert_eq!(vm.pc, 10);
+    }
+
+    struct IrqLine {
+        raised: bool,
+    }
+
+    impl Device for IrqLine {
+        fn range(&self) -> std::ops::RangeInclusive<u16> {
+            0x2000..=0x2000
+        }
+
+        fn on_read(&mut self, _addr: u16) -> u8 {
+            0
+        }
+
+        // Any write acknowledges the interrupt
+        fn on_write(&mut self, _addr: u16, _data: u8) {
+            self.raised = false;
+        }
+
+        fn interrupt(&self) -> bool {
+            self.raised
         }
     }
 
-    // CALL
-    fn inst_calla(&mut self, addr: u16) {
-        let pc = self.pc;
-        self.push_u16(pc);
-        self.pc = addr;
+    #[test]
+    fn test_interrupts() {
+        let mut vm = VM::new();
+        vm.attach_device(Box::new(IrqLine { raised: true }));
+        vm.install_code(&[
+            CodeSection {
+                org: 0,
+                bank: 0,
+                code: [
+                    &[opc::LDSPI as u8, 0x01, 0x00][..],
+                    &[opc::LOADI as u8, 1, 0x00, 0x01],
+                    &[opc::SEI as u8],
+                    &[opc::HALT as u8],
+                ]
+                .concat(),
+            },
+            CodeSection {
+                org: 0x40,
+                bank: 0,
+                code: [
+                    &[opc::LOADI as u8, 2, 0x00, 0x01][..],
+                    &[opc::STRA as u8, 2, 0x20, 0x00],
+                    &[opc::RTI as u8],
+                ]
+                .concat(),
+            },
+            CodeSection {
+                org: IRQ_VECTOR,
+                bank: 0,
+                code: vec![0x00, 0x40],
+            },
+        ]);
+        vm.run().unwrap();
+
+        // The handler ran once and returned to the HALT
+        assert_eq!(vm.read_reg(1).unwrap(), 1);
+        assert_eq!(vm.read_reg(2).unwrap(), 1);
+        assert_eq!(vm.pc, 0x09);
+        assert_eq!(vm.sp, 0x0100);
+        assert!(vm.flag(FLAG_INTERRUPT));
     }
 
-    fn inst_callr(&mut self, r: u8) {
-        let pc = self.pc;
-        self.push_u16(pc);
+    fn step_vm(code: &[&[u8]]) -> VM {
+        let mut vm = VM::new();
+        vm.install_code(&[CodeSection {
+            org: 0,
+            bank: 0,
+            code: code.concat(),
+        }]);
+        vm
+    }
 
-        let new_pc = self.read_reg(r);
-        self.pc = new_pc;
+    #[test]
+    fn test_bank_switching() {
+        // Call the subroutine at 0x8000 in bank 1 then bank 2
+        let main = [
+            &[opc::LDSPI as u8, 0x01, 0x00][..],
+            &[opc::LOADI as u8, 1, 0x00, 0x01],
+            &[opc::STRA as u8, 1, 0xFF, 0xFA],
+            &[opc::CALLA as u8, 0x80, 0x00],
+            &[opc::LOADI as u8, 1, 0x00, 0x02],
+            &[opc::STRA as u8, 1, 0xFF, 0xFA],
+            &[opc::CALLA as u8, 0x80, 0x00],
+            &[opc::HALT as u8],
+        ]
+        .concat();
+        let print = |c: u8| {
+            [
+                &[opc::LOADI as u8, 2, 0x00, c][..],
+                &[opc::STRA as u8, 2, 0xFF, 0xFD],
+                &[opc::RTN as u8],
+            ]
+            .concat()
+        };
+
+        let mut vm = VM::new();
+        vm.install_code(&[
+            CodeSection {
+                org: 0,
+                bank: 0,
+                code: main,
+            },
+            CodeSection {
+                org: 0x8000,
+                bank: 1,
+                code: print(b'A'),
+            },
+            CodeSection {
+                org: 0x8000,
+                bank: 2,
+                code: print(b'B'),
+            },
+        ]);
+        assert_eq!(vm.bank_count(), 3);
+        assert_eq!(vm.bank(), Some(0));
+
+        vm.run().unwrap();
+        assert_eq!(vm.output(), "AB");
+        assert_eq!(vm.bank(), Some(2));
+
+        let mut snapshot = Vec::new();
+        vm.save_snapshot(&mut snapshot).unwrap();
+        let mut restored = VM::new();
+        restored.load_snapshot(&mut snapshot.as_slice()).unwrap();
+        assert_eq!(restored.bank(), Some(2));
+        assert_eq!(restored.peek(0x8003), b'B');
+        restored.select_bank(1);
+        assert_eq!(restored.peek(0x8003), b'A');
     }
 
-    fn inst_rtn(&mut self) {
-        let pc = self.pop_u16();
-        self.pc = pc;
+    #[test]
+    fn test_protection() {
+        // Store %A over the last byte of the STR and the HALT at 0x0008
+        let code: &[&[u8]] = &[
+            &[opc::LOADI as u8, 0xA, 0x12, 0x34],
+            &[opc::STRA as u8, 0xA, 0x00, 0x07],
+            &[opc::HALT as u8],
+        ];
+
+        let mut vm = step_vm(code);
+        vm.protect(Region::rom(0x0008..=0x000F));
+        match vm.run().unwrap_err() {
+            VmError::AccessViolation(8, Access::Write, state) => assert_eq!(state.pc, 4),
+            e => panic!("unexpected error {}", e),
+        }
+        // Neither byte is written
+        assert_eq!(vm.peek(7), 0x07);
+        assert_eq!(vm.peek(8), opc::HALT as u8);
+
+        let mut vm = step_vm(code);
+        vm.protect(Region::no_execute(0x0002..=0x0003));
+        match vm.run().unwrap_err() {
+            VmError::AccessViolation(2, Access::Execute, state) => assert_eq!(state.pc, 0),
+            e => panic!("unexpected error {}", e),
+        }
+
+        // Writing the HALT with a PUSH is caught the same way
+        let mut vm = step_vm(&[
+            &[opc::LDSPI as u8, 0x00, 0x07],
+            &[opc::PUSH as u8, 0xA],
+            &[opc::HALT as u8],
+        ]);
+        vm.protect(Region::rom(0x0005..=0x0005));
+        assert!(matches!(
+            vm.run(),
+            Err(VmError::AccessViolation(5, Access::Write, _))
+        ));
+    }
+
+    #[test]
+    fn test_step_limit() {
+        let mut vm = step_vm(&[&[opc::JMPA as u8, 0x00, 0x00]]);
+        vm.set_max_steps(Some(100));
+
+        match vm.run().unwrap_err() {
+            VmError::StepLimit(100, state) => assert_eq!(state.pc, 0),
+            e => panic!("unexpected error {}", e),
+        }
+        assert_eq!(vm.steps(), 100);
+    }
+
+    #[test]
+    fn test_time_limit() {
+        let mut vm = step_vm(&[&[opc::JMPA as u8, 0x00, 0x00]]);
+        vm.set_max_time(Some(Duration::from_millis(10)));
+
+        assert!(matches!(vm.run().unwrap_err(), VmError::TimeLimit(..)));
+
+        // The clock starts with the next instruction, not the next check
+        let mut vm = step_vm(&[&[opc::JMPA as u8, 0x00, 0x00]]);
+        vm.step().unwrap();
+        vm.set_max_time(Some(Duration::from_millis(10)));
+        vm.step().unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(matches!(vm.run().unwrap_err(), VmError::TimeLimit(..)));
+        assert_eq!(vm.steps(), 1024);
+    }
+
+    impl VM {
+        // Step through breakpoints and watchpoints without the debugger
+        fn run_until_halt(&mut self) {
+            while self.step().unwrap() != StepOutcome::Halted {}
+        }
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        // Count %1 from 0 to 3
+        let mut vm = step_vm(&[
+            &[opc::ADDI as u8, 1, 0x00, 0x01],
+            &[opc::CMPI as u8, 1, 0x00, 0x03],
+            &[opc::JNZ as u8, 0x00, 0x00],
+            &[opc::HALT as u8],
+        ]);
+        vm.add_breakpoint(4, Some("%1 == 2".parse().unwrap()));
+        vm.add_breakpoint(11, None);
+
+        let mut outcome = StepOutcome::Continue;
+        while outcome == StepOutcome::Continue {
+            outcome = vm.step().unwrap();
+        }
+        assert_eq!(outcome, StepOutcome::Breakpoint(4));
+        assert_eq!(vm.read_reg(1).unwrap(), 2);
+
+        // Resuming executes the instruction under the breakpoint
+        assert_eq!(vm.step().unwrap(), StepOutcome::Continue);
+        assert_eq!(vm.pc, 8);
+
+        while outcome != StepOutcome::Breakpoint(11) {
+            outcome = vm.step().unwrap();
+        }
+        assert_eq!(vm.read_reg(1).unwrap(), 3);
+
+        assert!(vm.remove_breakpoint(11));
+        assert!(!vm.remove_breakpoint(11));
+        assert_eq!(vm.breakpoints().len(), 1);
+        assert_eq!(vm.step().unwrap(), StepOutcome::Halted);
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let mut vm = step_vm(&[
+            &[opc::LOADI as u8, 1, 0x00, 0x2A],
+            &[opc::STRA as u8, 1, 0x01, 0x00],
+            &[opc::LOADA as u8, 2, 0x01, 0x00],
+            &[opc::HALT as u8],
+        ]);
+        vm.add_watchpoint(0x0100..=0x010F, WatchKind::Write);
+        vm.add_watchpoint(0x0100..=0x0100, WatchKind::Read);
+
+        assert_eq!(vm.step().unwrap(), StepOutcome::Continue);
+        assert_eq!(
+            vm.step().unwrap(),
+            StepOutcome::Watchpoint(WatchHit {
+                addr: 0x0100,
+                kind: WatchKind::Write,
+                value: 0x2A
+            })
+        );
+        assert_eq!(
+            vm.step().unwrap(),
+            StepOutcome::Watchpoint(WatchHit {
+                addr: 0x0100,
+                kind: WatchKind::Read,
+                value: 0x2A
+            })
+        );
+
+        assert!(vm.remove_watchpoint(1).is_some());
+        assert!(vm.remove_watchpoint(1).is_none());
+        assert_eq!(vm.watchpoints().len(), 1);
+        assert_eq!(vm.step().unwrap(), StepOutcome::Halted);
+    }
+
+    #[test]
+    fn test_watchpoint_before_halt() {
+        let mut vm = VM::new();
+        vm.attach_device(Box::new(IrqLine { raised: true }));
+        vm.install_code(&[
+            CodeSection {
+                org: 0,
+                bank: 0,
+                code: [&[opc::LDSPI as u8, 0x01, 0x00][..], &[opc::SEI as u8]].concat(),
+            },
+            CodeSection {
+                org: 0x40,
+                bank: 0,
+                code: vec![opc::HALT as u8],
+            },
+            CodeSection {
+                org: IRQ_VECTOR,
+                bank: 0,
+                code: vec![0x00, 0x40],
+            },
+        ]);
+        vm.add_watchpoint(0x00F0..=0x00FF, WatchKind::Write);
+
+        assert_eq!(vm.step().unwrap(), StepOutcome::Continue);
+        assert_eq!(vm.step().unwrap(), StepOutcome::Continue);
+        // The interrupt pushes to the watched stack and the handler halts
+        assert!(matches!(
+            vm.step().unwrap(),
+            StepOutcome::Watchpoint(WatchHit {
+                kind: WatchKind::Write,
+                ..
+            })
+        ));
+        assert_eq!(vm.step().unwrap(), StepOutcome::Halted);
+    }
+
+    #[test]
+    fn test_step_back() {
+        let mut vm = step_vm(&[
+            &[opc::LOADI as u8, 0xA, 0x12, 0x34],
+            &[opc::STRA as u8, 0xA, 0x01, 0x00],
+            &[opc::ADDI as u8, 0xA, 0x00, 0x01],
+            &[opc::HALT as u8],
+        ]);
+        vm.add_breakpoint(4, None);
+        vm.run_until_halt();
+
+        assert_eq!(vm.history_len(), 4);
+        assert!(vm.step_back());
+        assert!(!vm.halted);
+        assert_eq!(vm.pc, 12);
+
+        assert!(vm.step_back());
+        assert_eq!(vm.read_reg(0xA).unwrap(), 0x1234);
+        assert!(vm.step_back());
+        assert_eq!(vm.bus.peek(0x100), 0);
+        assert_eq!(vm.bus.peek(0x101), 0);
+        assert_eq!(vm.pc, 4);
+
+        // Replaying executes the same instructions again
+        assert_eq!(vm.step().unwrap(), StepOutcome::Continue);
+        assert_eq!(vm.bus.peek(0x100), 0x12);
+
+        vm.run_until_halt();
+        assert_eq!(vm.reverse_continue().unwrap(), Some(4));
+        assert_eq!(vm.bus.peek(0x100), 0);
+        assert_eq!(vm.reverse_continue().unwrap(), None);
+        assert_eq!(vm.pc, 0);
+        assert_eq!(vm.read_reg(0xA).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_step_back_banks() {
+        // Write 0x55 to 0x8000 in bank 1, then switch back to bank 0
+        let mut vm = step_vm(&[
+            &[opc::LOADI as u8, 1, 0x00, 0x01],
+            &[opc::STRA as u8, 1, 0xFF, 0xFA],
+            &[opc::LOADI as u8, 2, 0x00, 0x55],
+            &[opc::STRA as u8, 2, 0x80, 0x00],
+            &[opc::LOADI as u8, 1, 0x00, 0x00],
+            &[opc::STRA as u8, 1, 0xFF, 0xFA],
+            &[opc::HALT as u8],
+        ]);
+        vm.enable_banking(2);
+        vm.bus.poke_bank(0, 0x8000, 0x22);
+        vm.bus.poke_bank(1, 0x8000, 0x11);
+        vm.run_until_halt();
+
+        let window = |vm: &VM, bank: u8| vm.bus.mmu().unwrap().bank(bank).unwrap()[0];
+        assert_eq!(vm.bank(), Some(0));
+        assert_eq!(window(&vm, 1), 0x55);
+
+        // Undo HALT and the switch back to bank 0
+        assert!(vm.step_back());
+        assert!(vm.step_back());
+        assert_eq!(vm.bank(), Some(1));
+        assert_eq!(vm.peek(0x8000), 0x55);
+
+        assert!(vm.step_back());
+        assert!(vm.step_back());
+        assert_eq!(vm.bank(), Some(1));
+        assert_eq!(window(&vm, 1), 0x11);
+        assert_eq!(window(&vm, 0), 0x22);
+
+        assert_eq!(vm.reverse_continue().unwrap(), None);
+        assert_eq!(vm.bank(), Some(0));
+        assert_eq!(window(&vm, 0), 0x22);
+        assert_eq!(window(&vm, 1), 0x11);
+    }
+
+    #[test]
+    fn test_history_limit() {
+        let mut vm = step_vm(&[
+            &[opc::NOOP as u8],
+            &[opc::NOOP as u8],
+            &[opc::NOOP as u8],
+            &[opc::HALT as u8],
+        ]);
+        vm.set_history_limit(2);
+        vm.run_until_halt();
+
+        assert_eq!(vm.history_len(), 2);
+        assert!(vm.step_back());
+        assert!(vm.step_back());
+        assert!(!vm.step_back());
+        assert_eq!(vm.pc, 2);
+
+        vm.set_history_limit(0);
+        vm.run_until_halt();
+        assert_eq!(vm.history_len(), 0);
+    }
+
+    #[test]
+    fn test_trace() {
+        let buf = OutputBuffer::new();
+        let mut vm = step_vm(&[
+            &[opc::LOADI as u8, 1, 0x00, b'A'],
+            &[opc::STRA as u8, 1, 0xFF, 0xFD],
+            &[opc::HALT as u8],
+        ]);
+        vm.set_trace(Box::new(buf.clone()));
+        vm.run().unwrap();
+        vm.finish_trace().unwrap();
+
+        let trace = buf.contents();
+        assert_eq!(
+            trace,
+            "0000  LOAD %1 #0x0041      R=00 41 00 00 00 00 00 00 00 00 SP=0000 F=00\n\
+             0004  STR %1 0xFFFD        R=00 41 00 00 00 00 00 00 00 00 SP=0000 F=00 W=FFFD:41\n\
+             0008  HALT                 R=00 41 00 00 00 00 00 00 00 00 SP=0000 F=00\n"
+        );
+    }
+
+    #[test]
+    fn test_trace_fault() {
+        let buf = OutputBuffer::new();
+        let mut vm = step_vm(&[&[opc::LDSPI as u8, 0x00, 0x01], &[opc::PUSH as u8, 0xA]]);
+        vm.set_trace(Box::new(buf.clone()));
+        assert!(matches!(vm.run(), Err(VmError::StackOverflow(_))));
+        vm.finish_trace().unwrap();
+
+        let trace = buf.contents();
+        assert_eq!(
+            trace,
+            "0000  LDSP #0x0001         R=00 00 00 00 00 00 00 00 00 00 SP=0001 F=00\n\
+             0003  PUSH %A              R=00 00 00 00 00 00 00 00 00 00 SP=0001 F=00\n"
+        );
+
+        // Interrupt entry pushes the return address before overflowing
+        let buf = OutputBuffer::new();
+        let mut vm = step_vm(&[&[opc::LDSPI as u8, 0x00, 0x02], &[opc::SEI as u8]]);
+        vm.attach_device(Box::new(IrqLine { raised: true }));
+        vm.set_trace(Box::new(buf.clone()));
+        assert!(matches!(vm.run(), Err(VmError::StackOverflow(_))));
+        vm.finish_trace().unwrap();
+        assert_eq!(
+            buf.contents(),
+            "0000  LDSP #0x0002         R=00 00 00 00 00 00 00 00 00 00 SP=0002 F=00\n\
+             0003  SEI                  R=00 00 00 00 00 00 00 00 00 00 SP=0002 F=10\n\
+             0004  IRQ                  R=00 00 00 00 00 00 00 00 00 00 SP=0000 F=10 W=0000:00,0001:04\n"
+        );
+    }
+
+    #[test]
+    fn test_profile() {
+        // Count %1 down from 3 then halt
+        let mut vm = step_vm(&[
+            &[opc::LOADI as u8, 1, 0x00, 3],
+            &[opc::SUBI as u8, 1, 0x00, 1],
+            &[opc::JNZ as u8, 0x00, 0x04],
+            &[opc::HALT as u8],
+        ]);
+        vm.enable_profile();
+        vm.run().unwrap();
+
+        let profile = vm.profile().unwrap();
+        assert_eq!(profile.total(), 8);
+        assert_eq!(profile.count(0, 0x0), 1);
+        assert_eq!(profile.count(0, 0x4), 3);
+        assert_eq!(profile.count(0, 0x5), 0);
+        assert_eq!(profile.opcode_count(opc::JNZ), 3);
+        assert_eq!(profile.hot_addresses(1), vec![(0, 0x4, 3)]);
+    }
+
+    #[test]
+    fn test_observer() {
+        #[derive(Default)]
+        struct Recorder {
+            events: Vec<String>,
+        }
+
+        impl Observer for Recorder {
+            fn before_instruction(&mut self, _vm: &VM, pc: u16) {
+                self.events.push(format!("before {:04X}", pc));
+            }
+
+            fn after_instruction(&mut self, vm: &VM, pc: u16, opcode: opc) {
+                self.events
+                    .push(format!("after {:04X} {:?} pc={:04X}", pc, opcode, vm.pc()));
+            }
+
+            fn on_memory_read(&mut self, addr: u16, data: u8) {
+                self.events.push(format!("read {:04X}:{:02X}", addr, data));
+            }
+
+            fn on_memory_write(&mut self, addr: u16, data: u8) {
+                self.events.push(format!("write {:04X}:{:02X}", addr, data));
+            }
+
+            fn on_register_write(&mut self, reg: u8, data: u16) {
+                self.events.push(format!("reg {:X}={:04X}", reg, data));
+            }
+
+            fn on_halt(&mut self, vm: &VM) {
+                self.events.push(format!("halt {}", vm.is_halted()));
+            }
+        }
+
+        let recorder = Rc::new(RefCell::new(Recorder::default()));
+        let mut vm = step_vm(&[
+            &[opc::LOADI as u8, 0xA, 0x12, 0x34],
+            &[opc::STRA as u8, 0xA, 0x01, 0x00],
+            &[opc::LOADA as u8, 1, 0x01, 0x01],
+            &[opc::ROTR as u8, 0xA, 4],
+            &[opc::CMPI as u8, 1, 0x00, 0x00],
+            &[opc::HALT as u8],
+        ]);
+        vm.add_observer(Box::new(recorder.clone()));
+        // Debugger changes aren't reported
+        vm.set_register(2, 0x42).unwrap();
+        vm.run().unwrap();
+
+        assert_eq!(
+            recorder.borrow().events,
+            vec![
+                "before 0000",
+                "reg A=1234",
+                "after 0000 LOADI pc=0004",
+                "before 0004",
+                "write 0100:12",
+                "write 0101:34",
+                "after 0004 STRA pc=0008",
+                "before 0008",
+                "read 0101:34",
+                "reg 1=0034",
+                "after 0008 LOADA pc=000C",
+                "before 000C",
+                "reg A=4123",
+                "after 000C ROTR pc=000F",
+                "before 000F",
+                "after 000F CMPI pc=0013",
+                "before 0013",
+                "after 0013 HALT pc=0014",
+                "halt true",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_output_streams() {
+        let out = OutputBuffer::new();
+        let mut vm = step_vm(&[
+            &[opc::LOADI as u8, 1, 0x00, b'A'],
+            &[opc::STRA as u8, 1, 0xFF, 0xFD],
+            &[opc::JMPA as u8, 0x00, 0x08],
+        ]);
+        vm.set_output(Box::new(out.clone()));
+        vm.set_max_steps(Some(10));
+
+        // Output is visible even though the program never halts
+        assert!(vm.run().is_err());
+        assert_eq!(out.contents(), "A");
+        assert_eq!(vm.output(), "");
     }
 }
@@ -0,0 +1,65 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+
+use crate::VM;
+use crate::opcodes::OpCode;
+
+/// Receives callbacks while the machine executes, for tools such as tracers,
+/// profilers and graders. Every method does nothing by default so an
+/// observer only implements the events it needs. Only the program's own
+/// actions are reported, not the debugger's or instruction fetches.
+pub trait Observer {
+    /// Called before the instruction at `pc` is fetched. If an interrupt was
+    /// just entered, `pc` is the start of the handler.
+    fn before_instruction(&mut self, _vm: &VM, _pc: u16) {}
+
+    /// Called after the instruction at `pc` executed and devices were ticked.
+    fn after_instruction(&mut self, _vm: &VM, _pc: u16, _opcode: OpCode) {}
+
+    /// A byte read from memory or a device.
+    fn on_memory_read(&mut self, _addr: u16, _data: u8) {}
+
+    /// A byte written to memory or a device, including stack pushes.
+    fn on_memory_write(&mut self, _addr: u16, _data: u8) {}
+
+    /// A single or double width register was written. Double width registers
+    /// are reported once with their full value.
+    fn on_register_write(&mut self, _reg: u8, _data: u16) {}
+
+    /// Called after a HALT instruction, following `after_instruction`.
+    fn on_halt(&mut self, _vm: &VM) {}
+}
+
+impl fmt::Debug for dyn Observer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Observer")
+    }
+}
+
+// Allows the owner of an observer to keep a handle to it after it's added
+impl<O: Observer> Observer for Rc<RefCell<O>> {
+    fn before_instruction(&mut self, vm: &VM, pc: u16) {
+        self.borrow_mut().before_instruction(vm, pc)
+    }
+
+    fn after_instruction(&mut self, vm: &VM, pc: u16, opcode: OpCode) {
+        self.borrow_mut().after_instruction(vm, pc, opcode)
+    }
+
+    fn on_memory_read(&mut self, addr: u16, data: u8) {
+        self.borrow_mut().on_memory_read(addr, data)
+    }
+
+    fn on_memory_write(&mut self, addr: u16, data: u8) {
+        self.borrow_mut().on_memory_write(addr, data)
+    }
+
+    fn on_register_write(&mut self, reg: u8, data: u16) {
+        self.borrow_mut().on_register_write(reg, data)
+    }
+
+    fn on_halt(&mut self, vm: &VM) {
+        self.borrow_mut().on_halt(vm)
+    }
+}
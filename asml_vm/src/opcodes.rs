@@ -1,5 +1,7 @@
+use std::fmt;
+
 #[repr(u8)]
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone, Copy)]
 pub enum OpCode {
     NOOP,
 
@@ -49,9 +51,205 @@ pub enum OpCode {
 
     DEBUG,
 
+    JZ,
+    JNZ,
+    JC,
+    JNC,
+    JN,
+    JV,
+
+    SUBA,
+    SUBI,
+    SUBR,
+
+    CMPA,
+    CMPI,
+    CMPR,
+
+    MULA,
+    MULI,
+    MULR,
+
+    DIVA,
+    DIVI,
+    DIVR,
+
+    MODA,
+    MODI,
+    MODR,
+
+    SHLA,
+    SHLI,
+    SHLR,
+
+    SHRA,
+    SHRI,
+    SHRR,
+
+    ASRA,
+    ASRI,
+    ASRR,
+
+    SEI,
+    CLI,
+    RTI,
+
     End, // Fake instruction marking end of enum list for conversion check
 }
 
+impl OpCode {
+    /// Convert a byte to an opcode, returning None for bytes that
+    /// aren't a valid instruction.
+    pub fn from_u8(i: u8) -> Option<OpCode> {
+        if i >= OpCode::End as u8 {
+            None
+        } else {
+            Some(OpCode::from(i))
+        }
+    }
+
+    /// The assembler mnemonic, shared by all modes of an instruction.
+    pub fn mnemonic(self) -> &'static str {
+        use self::OpCode::*;
+
+        match self {
+            NOOP => "NOOP",
+            ADDA | ADDI | ADDR => "ADD",
+            ANDA | ANDI | ANDR => "AND",
+            ORA | ORI | ORR => "OR",
+            XORA | XORI | XORR => "XOR",
+            ROTR => "ROTR",
+            ROTL => "ROTL",
+            CALLA | CALLR => "CALL",
+            RTN => "RTN",
+            HALT => "HALT",
+            JMP => "JMP",
+            JMPA => "JMPA",
+            LDSPA | LDSPI | LDSPR => "LDSP",
+            LOADA | LOADI | LOADR => "LOAD",
+            STRA | STRR => "STR",
+            XFER => "XFER",
+            POP => "POP",
+            PUSH => "PUSH",
+            DEBUG => "DEBUG",
+            JZ => "JZ",
+            JNZ => "JNZ",
+            JC => "JC",
+            JNC => "JNC",
+            JN => "JN",
+            JV => "JV",
+            SUBA | SUBI | SUBR => "SUB",
+            CMPA | CMPI | CMPR => "CMP",
+            MULA | MULI | MULR => "MUL",
+            DIVA | DIVI | DIVR => "DIV",
+            MODA | MODI | MODR => "MOD",
+            SHLA | SHLI | SHLR => "SHL",
+            SHRA | SHRI | SHRR => "SHR",
+            ASRA | ASRI | ASRR => "ASR",
+            SEI => "SEI",
+            CLI => "CLI",
+            RTI => "RTI",
+            End => "END",
+        }
+    }
+
+    pub fn operands(self) -> Operands {
+        use self::OpCode::*;
+
+        match self {
+            ADDA | ANDA | ORA | XORA | LOADA | STRA | SUBA | CMPA | MULA | DIVA | MODA | SHLA
+            | SHRA | ASRA | JMP => Operands::RegAddr,
+            ADDI | ANDI | ORI | XORI | LOADI | SUBI | CMPI | MULI | DIVI | MODI | SHLI | SHRI
+            | ASRI => Operands::RegImm,
+            ADDR | ANDR | ORR | XORR | LOADR | STRR | XFER | SUBR | CMPR | MULR | DIVR | MODR
+            | SHLR | SHRR | ASRR => Operands::RegReg,
+            ROTR | ROTL => Operands::RegByte,
+            CALLA | JMPA | LDSPA | JZ | JNZ | JC | JNC | JN | JV => Operands::Addr,
+            LDSPI => Operands::Imm,
+            CALLR | LDSPR | POP | PUSH => Operands::Reg,
+            NOOP | RTN | HALT | DEBUG | SEI | CLI | RTI | End => Operands::Inherent,
+        }
+    }
+}
+
+/// The layout of the arguments following an opcode.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Operands {
+    Inherent,
+    /// `%R`
+    Reg,
+    /// `HHLL`
+    Addr,
+    /// `#HHLL`
+    Imm,
+    /// `%R HHLL`
+    RegAddr,
+    /// `%R #HHLL`
+    RegImm,
+    /// `%R %R`
+    RegReg,
+    /// `%R #NN`
+    RegByte,
+}
+
+impl Operands {
+    /// Number of argument bytes
+    pub fn size(self) -> usize {
+        match self {
+            Operands::Inherent => 0,
+            Operands::Reg => 1,
+            Operands::Addr | Operands::Imm | Operands::RegReg | Operands::RegByte => 2,
+            Operands::RegAddr | Operands::RegImm => 3,
+        }
+    }
+}
+
+/// A decoded instruction with its argument bytes.
+#[derive(PartialEq, Debug, Clone)]
+pub struct Instruction {
+    pub opcode: OpCode,
+    pub args: Vec<u8>,
+}
+
+impl Instruction {
+    /// Decode the instruction at the start of `bytes`. Returns None if the
+    /// opcode is invalid or the arguments are cut off.
+    pub fn decode(bytes: &[u8]) -> Option<Instruction> {
+        let opcode = OpCode::from_u8(*bytes.first()?)?;
+        let args = bytes.get(1..=opcode.operands().size())?;
+
+        Some(Instruction {
+            opcode,
+            args: args.to_vec(),
+        })
+    }
+
+    /// Size of the instruction in bytes including the opcode
+    pub fn size(&self) -> usize {
+        self.args.len() + 1
+    }
+}
+
+/// Formats the instruction in assembler syntax, e.g. `LOAD %1 #0x0041`.
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let a = &self.args;
+        let word = |i: usize| (u16::from(a[i]) << 8) | u16::from(a[i + 1]);
+
+        write!(f, "{}", self.opcode.mnemonic())?;
+        match self.opcode.operands() {
+            Operands::Inherent => Ok(()),
+            Operands::Reg => write!(f, " %{:X}", a[0]),
+            Operands::Addr => write!(f, " 0x{:04X}", word(0)),
+            Operands::Imm => write!(f, " #0x{:04X}", word(0)),
+            Operands::RegAddr => write!(f, " %{:X} 0x{:04X}", a[0], word(1)),
+            Operands::RegImm => write!(f, " %{:X} #0x{:04X}", a[0], word(1)),
+            Operands::RegReg => write!(f, " %{:X} %{:X}", a[0], a[1]),
+            Operands::RegByte => write!(f, " %{:X} #0x{:02X}", a[0], a[1]),
+        }
+    }
+}
+
 impl From<u8> for OpCode {
     fn from(i: u8) -> OpCode {
         if i > OpCode::End as u8 {
@@ -81,4 +279,31 @@ mod test {
 
         assert_eq!(op, OpCode::NOOP);
     }
+
+    #[test]
+    fn test_opcode_from_u8() {
+        assert_eq!(OpCode::from_u8(2), Some(OpCode::ADDI));
+        assert_eq!(OpCode::from_u8(OpCode::End as u8), None);
+        assert_eq!(OpCode::from_u8(255), None);
+    }
+
+    #[test]
+    fn test_decode_instruction() {
+        let inst = Instruction::decode(&[OpCode::LOADI as u8, 1, 0x00, 0x41, 0xFF]).unwrap();
+        assert_eq!(inst.size(), 4);
+        assert_eq!(inst.to_string(), "LOAD %1 #0x0041");
+
+        let inst = Instruction::decode(&[OpCode::STRR as u8, 0xA, 2]).unwrap();
+        assert_eq!(inst.to_string(), "STR %A %2");
+
+        let inst = Instruction::decode(&[OpCode::JZ as u8, 0xC0, 0x00]).unwrap();
+        assert_eq!(inst.to_string(), "JZ 0xC000");
+
+        let inst = Instruction::decode(&[OpCode::HALT as u8]).unwrap();
+        assert_eq!(inst.to_string(), "HALT");
+
+        assert_eq!(Instruction::decode(&[OpCode::LOADI as u8, 1]), None);
+        assert_eq!(Instruction::decode(&[0xFF]), None);
+        assert_eq!(Instruction::decode(&[]), None);
+    }
 }
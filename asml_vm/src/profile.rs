@@ -0,0 +1,382 @@
+use std::fmt;
+use std::io::{self, Write};
+
+use crate::CodeSection;
+use crate::devices::{BANK_SIZE, BANK_WINDOW};
+use crate::opcodes::OpCode;
+use crate::symbols::SymbolTable;
+
+const NUM_OF_ADDRESSES: usize = 65536;
+
+// A counter per address. Addresses in the bank window are counted per bank,
+// without banking everything there is bank 0.
+#[derive(Clone)]
+struct Counters {
+    memory: Vec<u64>,
+    banks: Vec<Vec<u64>>,
+}
+
+impl Counters {
+    fn new() -> Self {
+        Counters {
+            memory: vec![0; NUM_OF_ADDRESSES],
+            banks: Vec::new(),
+        }
+    }
+
+    fn get(&self, bank: u8, addr: u16) -> u64 {
+        if BANK_WINDOW.contains(&addr) {
+            self.banks
+                .get(usize::from(bank))
+                .map_or(0, |b| b[offset(addr)])
+        } else {
+            self.memory[usize::from(addr)]
+        }
+    }
+
+    fn add(&mut self, bank: u8, addr: u16) {
+        if BANK_WINDOW.contains(&addr) {
+            let bank = usize::from(bank);
+            if bank >= self.banks.len() {
+                self.banks.resize(bank + 1, vec![0; BANK_SIZE]);
+            }
+            self.banks[bank][offset(addr)] += 1;
+        } else {
+            self.memory[usize::from(addr)] += 1;
+        }
+    }
+
+    // Every non-zero counter as (bank, address, count)
+    fn used(&self) -> Vec<(u8, u16, u64)> {
+        let memory = self
+            .memory
+            .iter()
+            .enumerate()
+            .map(|(addr, count)| (0, addr as u16, *count));
+        let banks = self.banks.iter().enumerate().flat_map(|(bank, counts)| {
+            counts
+                .iter()
+                .enumerate()
+                .map(move |(i, count)| (bank as u8, BANK_WINDOW.start() + i as u16, *count))
+        });
+        memory
+            .chain(banks)
+            .filter(|(_, _, count)| *count > 0)
+            .collect()
+    }
+}
+
+fn offset(addr: u16) -> usize {
+    usize::from(addr - BANK_WINDOW.start())
+}
+
+// An address, with its bank when it's in a bank other than 0
+fn location(bank: u8, addr: u16) -> String {
+    if bank > 0 {
+        format!("{}:0x{:04X}", bank, addr)
+    } else {
+        format!("0x{:04X}", addr)
+    }
+}
+
+/// Execution counters collected while profiling is enabled. Code in the bank
+/// window is counted separately for each bank.
+#[derive(Clone)]
+pub struct Profile {
+    /// Times an instruction started at each address
+    instructions: Counters,
+    /// Times each byte was executed as part of an instruction
+    bytes: Counters,
+    opcodes: Vec<u64>,
+    total: u64,
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Profile::new()
+    }
+}
+
+impl Profile {
+    pub fn new() -> Self {
+        Profile {
+            instructions: Counters::new(),
+            bytes: Counters::new(),
+            opcodes: vec![0; OpCode::End as usize],
+            total: 0,
+        }
+    }
+
+    pub(crate) fn record(&mut self, bank: u8, addr: u16, opcode: OpCode) {
+        self.instructions.add(bank, addr);
+        for i in 0..=opcode.operands().size() {
+            self.bytes.add(bank, addr.wrapping_add(i as u16));
+        }
+        self.opcodes[opcode as usize] += 1;
+        self.total += 1;
+    }
+
+    /// Total number of executed instructions
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    /// Times the instruction at `addr` was executed. `bank` is only used
+    /// for addresses in the bank window.
+    pub fn count(&self, bank: u8, addr: u16) -> u64 {
+        self.instructions.get(bank, addr)
+    }
+
+    pub fn opcode_count(&self, opcode: OpCode) -> u64 {
+        self.opcodes[opcode as usize]
+    }
+
+    /// The `n` most executed instruction addresses as (bank, address,
+    /// count), most executed first.
+    pub fn hot_addresses(&self, n: usize) -> Vec<(u8, u16, u64)> {
+        let mut hot = self.instructions.used();
+        hot.sort_by(|a, b| b.2.cmp(&a.2).then((a.0, a.1).cmp(&(b.0, b.1))));
+        hot.truncate(n);
+        hot
+    }
+
+    /// Executed opcodes with their counts, most executed first.
+    pub fn opcode_histogram(&self) -> Vec<(OpCode, u64)> {
+        let mut hist: Vec<(OpCode, u64)> = self
+            .opcodes
+            .iter()
+            .enumerate()
+            .filter(|(_, count)| **count > 0)
+            .filter_map(|(op, count)| Some((OpCode::from_u8(op as u8)?, *count)))
+            .collect();
+        hist.sort_by(|a, b| b.1.cmp(&a.1).then((a.0 as u8).cmp(&(b.0 as u8))));
+        hist
+    }
+
+    /// Split the assembled bytes into runs with the same execution count.
+    pub fn coverage(&self, code: &[CodeSection]) -> Vec<CoverageRange> {
+        let mut sections: Vec<&CodeSection> = code.iter().filter(|s| !s.code.is_empty()).collect();
+        sections.sort_by_key(|s| (s.bank, s.org));
+
+        let mut ranges: Vec<CoverageRange> = Vec::new();
+        for section in sections {
+            let start = usize::from(section.org);
+            let bank = section.bank;
+
+            for addr in start..start + section.code.len() {
+                let count = self.bytes.get(bank, addr as u16);
+
+                match ranges.last_mut() {
+                    Some(r)
+                        if r.bank == bank && usize::from(r.end) + 1 == addr && r.count == count =>
+                    {
+                        r.end = addr as u16
+                    }
+                    _ => ranges.push(CoverageRange {
+                        bank,
+                        start: addr as u16,
+                        end: addr as u16,
+                        count,
+                    }),
+                }
+            }
+        }
+        ranges
+    }
+
+    /// Write the coverage of `code` with one range per line as
+    /// `START-END COUNT` in hex and decimal: `0010-001B 3`.
+    pub fn write_coverage(&self, out: &mut dyn Write, code: &[CodeSection]) -> io::Result<()> {
+        for range in self.coverage(code) {
+            writeln!(out, "{}", range)?;
+        }
+        Ok(())
+    }
+
+    /// Write a human readable summary of the profile.
+    pub fn write_report(
+        &self,
+        out: &mut dyn Write,
+        code: &[CodeSection],
+        symbols: Option<&SymbolTable>,
+    ) -> io::Result<()> {
+        let label = |addr: u16| symbols.and_then(|s| s.resolve(addr)).unwrap_or_default();
+
+        writeln!(out, "Instructions executed: {}", self.total)?;
+
+        writeln!(out, "\nHot addresses:")?;
+        for (bank, addr, count) in self.hot_addresses(10) {
+            let line = format!(
+                "  {:<6} {:>10}  {}",
+                location(bank, addr),
+                count,
+                label(addr)
+            );
+            writeln!(out, "{}", line.trim_end())?;
+        }
+
+        writeln!(out, "\nInstruction counts:")?;
+        for (opcode, count) in self.opcode_histogram() {
+            writeln!(out, "  {:<6} {:>10}", format!("{:?}", opcode), count)?;
+        }
+
+        let coverage = self.coverage(code);
+        let total: usize = coverage.iter().map(|r| r.size()).sum();
+        let unexecuted: Vec<&CoverageRange> = coverage.iter().filter(|r| r.count == 0).collect();
+        let never: usize = unexecuted.iter().map(|r| r.size()).sum();
+
+        writeln!(
+            out,
+            "\nCoverage: {} of {} bytes executed",
+            total - never,
+            total
+        )?;
+        for range in unexecuted {
+            let line = format!(
+                "  never executed {}-0x{:04X}  {}",
+                location(range.bank, range.start),
+                range.end,
+                label(range.start)
+            );
+            writeln!(out, "{}", line.trim_end())?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Profile")
+            .field("total", &self.total)
+            .finish()
+    }
+}
+
+/// An inclusive range of assembled bytes that were each executed `count`
+/// times. `bank` is the bank of code in the bank window, 0 anywhere else.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CoverageRange {
+    pub bank: u8,
+    pub start: u16,
+    pub end: u16,
+    pub count: u64,
+}
+
+impl CoverageRange {
+    /// Number of bytes in the range
+    pub fn size(&self) -> usize {
+        usize::from(self.end - self.start) + 1
+    }
+}
+
+impl fmt::Display for CoverageRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.bank > 0 {
+            write!(f, "{}:", self.bank)?;
+        }
+        write!(f, "{:04X}-{:04X} {}", self.start, self.end, self.count)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_coverage() {
+        let mut profile = Profile::new();
+        profile.record(0, 0x10, OpCode::LOADI);
+        profile.record(0, 0x14, OpCode::JMPA);
+        profile.record(0, 0x14, OpCode::JMPA);
+
+        let code = vec![
+            CodeSection {
+                org: 0x10,
+                bank: 0,
+                code: vec![0; 8],
+            },
+            CodeSection {
+                org: 0x18,
+                bank: 0,
+                code: vec![0; 2],
+            },
+            CodeSection {
+                org: 0x100,
+                bank: 0,
+                code: vec![0],
+            },
+        ];
+
+        let mut out = Vec::new();
+        profile.write_coverage(&mut out, &code).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "0010-0013 1\n0014-0016 2\n0017-0019 0\n0100-0100 0\n"
+        );
+
+        let mut symbols = SymbolTable::new();
+        symbols.insert("main", 0x10);
+        symbols.insert("data", 0x17);
+
+        let mut out = Vec::new();
+        profile
+            .write_report(&mut out, &code, Some(&symbols))
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "Instructions executed: 3
+
+Hot addresses:
+  0x0014          2  main+4
+  0x0010          1  main
+
+Instruction counts:
+  JMPA            2
+  LOADI           1
+
+Coverage: 7 of 11 bytes executed
+  never executed 0x0017-0x0019  data
+  never executed 0x0100-0x0100  data+233
+"
+        );
+    }
+
+    #[test]
+    fn test_banked_coverage() {
+        // Code at the same address in two banks, only bank 1 runs
+        let mut profile = Profile::new();
+        profile.record(1, 0x8000, OpCode::HALT);
+        profile.record(1, 0x8000, OpCode::HALT);
+        profile.record(0, 0x0000, OpCode::JMPA);
+
+        let code = vec![
+            CodeSection {
+                org: 0x8000,
+                bank: 2,
+                code: vec![0; 2],
+            },
+            CodeSection {
+                org: 0,
+                bank: 0,
+                code: vec![0; 3],
+            },
+            CodeSection {
+                org: 0x8000,
+                bank: 1,
+                code: vec![0; 2],
+            },
+        ];
+
+        assert_eq!(profile.count(1, 0x8000), 2);
+        assert_eq!(profile.count(2, 0x8000), 0);
+        assert_eq!(profile.count(0, 0x8000), 0);
+        assert_eq!(profile.hot_addresses(2), vec![(1, 0x8000, 2), (0, 0, 1)]);
+
+        let mut out = Vec::new();
+        profile.write_coverage(&mut out, &code).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "0000-0002 1\n1:8000-8000 2\n1:8001-8001 0\n2:8000-8001 0\n"
+        );
+    }
+}
@@ -0,0 +1,107 @@
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+use crate::breakpoints::parse_addr;
+
+/// A kind of memory access that a region can forbid.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    Write,
+    Execute,
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Access::Write => write!(f, "write"),
+            Access::Execute => write!(f, "execute"),
+        }
+    }
+}
+
+/// Protection for a range of memory. Writing to a read-only region or
+/// fetching an instruction byte from a no-execute region stops the machine
+/// with `VmError::AccessViolation`. Loading code and the debugger's `poke`
+/// ignore protection.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Region {
+    pub range: RangeInclusive<u16>,
+    pub read_only: bool,
+    pub no_execute: bool,
+}
+
+impl Region {
+    /// A read-only region that can be executed.
+    pub fn rom(range: RangeInclusive<u16>) -> Self {
+        Region {
+            range,
+            read_only: true,
+            no_execute: false,
+        }
+    }
+
+    /// A writable data region that can't be executed.
+    pub fn no_execute(range: RangeInclusive<u16>) -> Self {
+        Region {
+            range,
+            read_only: false,
+            no_execute: true,
+        }
+    }
+
+    pub(crate) fn forbids(&self, addr: u16, access: Access) -> bool {
+        let forbidden = match access {
+            Access::Write => self.read_only,
+            Access::Execute => self.no_execute,
+        };
+        forbidden && self.range.contains(&addr)
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "0x{:04X}-0x{:04X}", self.range.start(), self.range.end())?;
+        if self.read_only {
+            write!(f, " read-only")?;
+        }
+        if self.no_execute {
+            write!(f, " no-execute")?;
+        }
+        Ok(())
+    }
+}
+
+/// An inclusive hex address range such as `0000-00FF`, or a single address.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AddrRange(pub RangeInclusive<u16>);
+
+impl FromStr for AddrRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = s.split_once('-').unwrap_or((s, s));
+        let parse =
+            |a: &str| parse_addr(a.trim()).ok_or_else(|| format!("invalid address `{}`", a));
+
+        let (start, end) = (parse(start)?, parse(end)?);
+        if start > end {
+            return Err(format!("range `{}` ends before it starts", s));
+        }
+        Ok(AddrRange(start..=end))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!("0000-00FF".parse(), Ok(AddrRange(0..=0xFF)));
+        assert_eq!("0xF000-0xFFFF".parse(), Ok(AddrRange(0xF000..=0xFFFF)));
+        assert_eq!("FFFE".parse(), Ok(AddrRange(0xFFFE..=0xFFFE)));
+        assert!("0100-00FF".parse::<AddrRange>().is_err());
+        assert!("start-0x10".parse::<AddrRange>().is_err());
+    }
+}
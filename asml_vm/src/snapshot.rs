@@ -0,0 +1,264 @@
+//! Saving and restoring the complete machine state.
+//!
+//! A snapshot is a binary file starting with the 8 byte magic `ASMLSNAP` and
+//! a 1 byte format version, followed by chunks. Each chunk is a 4 byte ASCII
+//! tag, a 4 byte big endian data length and the data. Unknown chunks are
+//! skipped when loading.
+//!
+//! | Tag    | Data                                                           |
+//! |--------|----------------------------------------------------------------|
+//! | `CPU ` | PC (2), SP (2), flags (1), halted (1), registers 0 - 9 (10)    |
+//! | `MEM ` | All 65536 bytes of main memory                                 |
+//! | `PRNT` | Printer output as UTF-8                                        |
+//! | `BANK` | Selected bank (1), then each 16K memory bank in order          |
+//!
+//! `MEM ` holds the selected bank in the bank window. `BANK` is only written
+//! when banking is enabled. Multi-byte values are big endian. Breakpoints,
+//! undo history and device state other than the printer output are not
+//! saved. Printer output is only kept when the printer writes to memory, see
+//! `VM::set_output`.
+
+use std::io::{self, Read, Write};
+
+use crate::devices::{BANK_SIZE, BANK_WINDOW, MAX_BANKS};
+use crate::{NUM_OF_MEMORY_CELLS, NUM_OF_REGISTERS, VM};
+
+const MAGIC: &[u8; 8] = b"ASMLSNAP";
+const VERSION: u8 = 1;
+
+const CHUNK_CPU: &[u8; 4] = b"CPU ";
+const CHUNK_MEMORY: &[u8; 4] = b"MEM ";
+const CHUNK_PRINTER: &[u8; 4] = b"PRNT";
+const CHUNK_BANKS: &[u8; 4] = b"BANK";
+
+const CPU_LEN: usize = 6 + NUM_OF_REGISTERS;
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("invalid snapshot: {}", msg),
+    )
+}
+
+fn write_chunk(out: &mut dyn Write, tag: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    out.write_all(tag)?;
+    out.write_all(&(data.len() as u32).to_be_bytes())?;
+    out.write_all(data)
+}
+
+// Returns None at the end of the input
+fn read_chunk(input: &mut dyn Read) -> io::Result<Option<([u8; 4], Vec<u8>)>> {
+    let mut tag = [0; 4];
+    match input.read_exact(&mut tag) {
+        Ok(_) => {}
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+
+    let mut len = [0; 4];
+    input.read_exact(&mut len)?;
+
+    let mut data = Vec::new();
+    input
+        .take(u64::from(u32::from_be_bytes(len)))
+        .read_to_end(&mut data)?;
+    if data.len() != u32::from_be_bytes(len) as usize {
+        return Err(invalid("truncated chunk"));
+    }
+
+    Ok(Some((tag, data)))
+}
+
+impl VM {
+    /// Write the machine state to `out`. See the module docs for the format.
+    pub fn save_snapshot(&self, out: &mut dyn Write) -> io::Result<()> {
+        out.write_all(MAGIC)?;
+        out.write_all(&[VERSION])?;
+
+        let mut cpu = Vec::with_capacity(CPU_LEN);
+        cpu.extend_from_slice(&self.pc.to_be_bytes());
+        cpu.extend_from_slice(&self.sp.to_be_bytes());
+        cpu.push(self.flags);
+        cpu.push(self.halted as u8);
+        cpu.extend_from_slice(&self.registers);
+        write_chunk(out, CHUNK_CPU, &cpu)?;
+
+        let memory: Vec<u8> = (0..NUM_OF_MEMORY_CELLS)
+            .map(|addr| self.bus.peek(addr as u16))
+            .collect();
+        write_chunk(out, CHUNK_MEMORY, &memory)?;
+
+        write_chunk(out, CHUNK_PRINTER, self.output().as_bytes())?;
+
+        if let Some(mmu) = self.bus.mmu() {
+            let mut banks = Vec::with_capacity(1 + mmu.count() * BANK_SIZE);
+            banks.push(mmu.selected());
+            for bank in 0..mmu.count() {
+                banks.extend_from_slice(mmu.bank(bank as u8).unwrap_or_default());
+            }
+            write_chunk(out, CHUNK_BANKS, &banks)?;
+        }
+        out.flush()
+    }
+
+    /// Replace the machine state with a snapshot written by `save_snapshot`.
+    /// The machine is left untouched if the snapshot is invalid.
+    pub fn load_snapshot(&mut self, input: &mut dyn Read) -> io::Result<()> {
+        let mut header = [0; 9];
+        input.read_exact(&mut header)?;
+        if &header[..8] != MAGIC {
+            return Err(invalid("bad magic"));
+        }
+        if header[8] != VERSION {
+            return Err(invalid(&format!("unsupported version {}", header[8])));
+        }
+
+        let mut cpu = None;
+        let mut memory = None;
+        let mut printer = String::new();
+        let mut banks = None;
+
+        while let Some((tag, data)) = read_chunk(input)? {
+            match &tag {
+                CHUNK_CPU if data.len() == CPU_LEN => cpu = Some(data),
+                CHUNK_MEMORY if data.len() == NUM_OF_MEMORY_CELLS => memory = Some(data),
+                CHUNK_PRINTER => {
+                    printer = String::from_utf8(data).map_err(|_| invalid("printer isn't UTF-8"))?
+                }
+                CHUNK_BANKS if data.len() > 1 && (data.len() - 1) % BANK_SIZE == 0 => {
+                    if (data.len() - 1) / BANK_SIZE > MAX_BANKS {
+                        return Err(invalid("too many banks"));
+                    }
+                    banks = Some(data)
+                }
+                CHUNK_CPU | CHUNK_MEMORY | CHUNK_BANKS => {
+                    return Err(invalid("bad chunk length"));
+                }
+                _ => {}
+            }
+        }
+
+        let cpu = cpu.ok_or_else(|| invalid("missing CPU chunk"))?;
+        let memory = memory.ok_or_else(|| invalid("missing MEM chunk"))?;
+
+        self.pc = u16::from_be_bytes([cpu[0], cpu[1]]);
+        self.sp = u16::from_be_bytes([cpu[2], cpu[3]]);
+        self.flags = cpu[4];
+        self.halted = cpu[5] != 0;
+        self.registers = cpu[6..].to_vec();
+        if let Some(banks) = banks {
+            let contents = banks[1..].chunks(BANK_SIZE);
+            let count = contents.len();
+            self.enable_banking(count);
+            for (bank, data) in contents.enumerate() {
+                for (offset, b) in data.iter().enumerate() {
+                    let addr = BANK_WINDOW.start() + offset as u16;
+                    self.bus.poke_bank(bank as u8, addr, *b);
+                }
+            }
+            // Banking can't be shrunk, so banks the snapshot doesn't have are
+            // left empty
+            if let Some(mmu) = self.bus.mmu_mut() {
+                for bank in count..mmu.count() {
+                    if let Some(data) = mmu.bank_mut(bank as u8) {
+                        data.fill(0);
+                    }
+                }
+            }
+            self.select_bank(banks[0]);
+        }
+        for (addr, b) in memory.into_iter().enumerate() {
+            self.bus.poke(addr as u16, b);
+        }
+        self.printer.borrow_mut().restore_output(&printer);
+
+        self.inst_pc = self.pc;
+        self.resume_from = None;
+        self.history.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::CodeSection;
+    use crate::opcodes::OpCode as opc;
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let mut vm = VM::new();
+        vm.install_code(&[CodeSection {
+            org: 0,
+            bank: 0,
+            code: [
+                &[opc::LOADI as u8, 0xA, 0x12, 0x34][..],
+                &[opc::LDSPI as u8, 0x01, 0x00],
+                &[opc::LOADI as u8, 1, 0x00, b'A'],
+                &[opc::STRA as u8, 1, 0xFF, 0xFD],
+                &[opc::HALT as u8],
+            ]
+            .concat(),
+        }]);
+        vm.step().unwrap();
+        vm.step().unwrap();
+        vm.step().unwrap();
+        vm.step().unwrap();
+
+        let mut snapshot = Vec::new();
+        vm.save_snapshot(&mut snapshot).unwrap();
+        assert_eq!(&snapshot[..9], b"ASMLSNAP\x01");
+
+        let mut restored = VM::new();
+        restored.load_snapshot(&mut snapshot.as_slice()).unwrap();
+        assert_eq!(restored.pc, 15);
+        assert_eq!(restored.sp, 0x100);
+        assert_eq!(restored.registers, vm.registers);
+        assert_eq!(restored.output(), "A");
+        assert_eq!(restored.bus.peek(0), opc::LOADI as u8);
+
+        restored.run().unwrap();
+        assert!(restored.halted);
+    }
+
+    #[test]
+    fn test_banked_snapshot() {
+        let mut vm = VM::new();
+        vm.enable_banking(2);
+        vm.bus.poke_bank(1, 0x8000, 0x42);
+        vm.select_bank(1);
+        let mut snapshot = Vec::new();
+        vm.save_snapshot(&mut snapshot).unwrap();
+
+        let mut restored = VM::new();
+        restored.enable_banking(4);
+        restored.bus.poke_bank(3, 0x8000, 0xFF);
+        restored.load_snapshot(&mut snapshot.as_slice()).unwrap();
+        assert_eq!(restored.bank(), Some(1));
+        assert_eq!(restored.bus.peek(0x8000), 0x42);
+        let mmu = restored.bus.mmu().unwrap();
+        assert_eq!(mmu.bank(1).unwrap()[0], 0x42);
+        assert_eq!(mmu.bank(3).unwrap()[0], 0);
+
+        // One more bank than the machine can have
+        let banks = vec![0; 1 + (MAX_BANKS + 1) * BANK_SIZE];
+        write_chunk(&mut snapshot, CHUNK_BANKS, &banks).unwrap();
+        let err = restored
+            .load_snapshot(&mut snapshot.as_slice())
+            .unwrap_err();
+        assert_eq!(err.to_string(), "invalid snapshot: too many banks");
+        assert_eq!(restored.bus.mmu().unwrap().bank(1).unwrap()[0], 0x42);
+    }
+
+    #[test]
+    fn test_invalid_snapshot() {
+        let mut vm = VM::new();
+        assert!(vm.load_snapshot(&mut &b"ASMLSNAQ\x01"[..]).is_err());
+        assert!(vm.load_snapshot(&mut &b"ASMLSNAP\x02"[..]).is_err());
+        assert!(vm.load_snapshot(&mut &b"ASMLSNAP\x01"[..]).is_err());
+        assert!(
+            vm.load_snapshot(&mut &b"ASMLSNAP\x01CPU \x00\x00\x00\x10\x00"[..])
+                .is_err()
+        );
+    }
+}
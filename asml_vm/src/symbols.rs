@@ -0,0 +1,75 @@
+use std::collections::{BTreeMap, HashMap};
+
+/// Label addresses from the assembler, used to show addresses as labels.
+#[derive(Debug, Clone, Default)]
+pub struct SymbolTable {
+    by_name: HashMap<String, u16>,
+    // When several labels share an address the first name sorted is used
+    by_addr: BTreeMap<u16, String>,
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        SymbolTable::default()
+    }
+
+    pub fn insert(&mut self, name: &str, addr: u16) {
+        self.by_name.insert(name.to_owned(), addr);
+
+        match self.by_addr.get(&addr) {
+            Some(existing) if existing.as_str() <= name => {}
+            _ => {
+                self.by_addr.insert(addr, name.to_owned());
+            }
+        }
+    }
+
+    pub fn lookup(&self, name: &str) -> Option<u16> {
+        self.by_name.get(name).cloned()
+    }
+
+    /// Name an address relative to the closest label at or before it, e.g.
+    /// `loop` or `loop+4`.
+    pub fn resolve(&self, addr: u16) -> Option<String> {
+        let (label_addr, name) = self.nearest(addr)?;
+
+        if label_addr == addr {
+            Some(name.to_owned())
+        } else {
+            Some(format!("{}+{}", name, addr - label_addr))
+        }
+    }
+
+    /// The closest label at or before `addr`.
+    pub fn nearest(&self, addr: u16) -> Option<(u16, &str)> {
+        self.by_addr
+            .range(..=addr)
+            .next_back()
+            .map(|(addr, name)| (*addr, name.as_str()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_name.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_resolve() {
+        let mut symbols = SymbolTable::new();
+        symbols.insert("main", 0x10);
+        symbols.insert("loop", 0x20);
+        symbols.insert("again", 0x20);
+
+        assert_eq!(symbols.resolve(0x05), None);
+        assert_eq!(symbols.resolve(0x10).unwrap(), "main");
+        assert_eq!(symbols.resolve(0x14).unwrap(), "main+4");
+        assert_eq!(symbols.resolve(0x20).unwrap(), "again");
+        assert_eq!(symbols.resolve(0xFFFF).unwrap(), "again+65503");
+        assert_eq!(symbols.lookup("loop"), Some(0x20));
+        assert_eq!(symbols.lookup("nope"), None);
+    }
+}
@@ -0,0 +1,80 @@
+use std::fmt;
+use std::io::{self, Write};
+
+/// Writes one line per executed instruction. The format is stable so traces
+/// from different runs can be diffed:
+///
+/// ```text
+/// 0000  LOAD %1 #0x0041      R=00 41 00 00 00 00 00 00 00 00 SP=0000 F=00
+/// 0004  STR %1 0xFFFD        R=00 41 00 00 00 00 00 00 00 00 SP=0000 F=00 W=FFFD:41
+/// ```
+///
+/// Registers, SP and flags are the values after the instruction executed.
+/// Memory writes are listed in the order they happened. Interrupt entry is
+/// traced as its own `IRQ` line. An instruction that fails with an error is
+/// still traced, with the state it left and the writes it made.
+pub struct Tracer {
+    out: Box<dyn Write>,
+    writes: Vec<(u16, u8)>,
+    error: Option<io::Error>,
+}
+
+impl Tracer {
+    pub fn new(out: Box<dyn Write>) -> Tracer {
+        Tracer {
+            out,
+            writes: Vec::new(),
+            error: None,
+        }
+    }
+
+    pub(crate) fn record_write(&mut self, addr: u16, data: u8) {
+        self.writes.push((addr, data));
+    }
+
+    pub(crate) fn record(
+        &mut self,
+        pc: u16,
+        inst: &dyn fmt::Display,
+        registers: &[u8],
+        sp: u16,
+        flags: u8,
+    ) {
+        let mut line = format!("{:04X}  {:<20} R=", pc, inst.to_string());
+        for (i, reg) in registers.iter().enumerate() {
+            if i > 0 {
+                line.push(' ');
+            }
+            line.push_str(&format!("{:02X}", reg));
+        }
+        line.push_str(&format!(" SP={:04X} F={:02X}", sp, flags));
+
+        for (i, (addr, data)) in self.writes.drain(..).enumerate() {
+            let sep = if i == 0 { " W=" } else { "," };
+            line.push_str(&format!("{}{:04X}:{:02X}", sep, addr, data));
+        }
+
+        // Keep the first error and stop writing, it's reported by finish
+        if self.error.is_none()
+            && let Err(e) = writeln!(self.out, "{}", line)
+        {
+            self.error = Some(e);
+        }
+    }
+
+    /// Flush the trace, returning the first error hit while writing it.
+    pub fn finish(mut self) -> io::Result<()> {
+        match self.error.take() {
+            Some(e) => Err(e),
+            None => self.out.flush(),
+        }
+    }
+}
+
+impl fmt::Debug for Tracer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Tracer")
+            .field("writes", &self.writes)
+            .finish()
+    }
+}
@@ -96,10 +96,8 @@ pub fn parse_file(p: &Path) -> Result<Srecord> {
         }
 
         match record.rec_type {
-            SrecCount16 | SrecCount24 => {
-                if records.len() - 1 != record.address as usize {
-                    invalid_line!("count doesn't match number of data lines");
-                }
+            SrecCount16 | SrecCount24 if records.len() - 1 != record.address as usize => {
+                invalid_line!("count doesn't match number of data lines");
             }
             _ => {}
         }
@@ -113,7 +111,7 @@ pub fn parse_file(p: &Path) -> Result<Srecord> {
 fn convert_hex(bytes: &[u8]) -> Vec<u8> {
     let mut converted = Vec::new();
 
-    if bytes.len() % 2 != 0 {
+    if !bytes.len().is_multiple_of(2) {
         return converted;
     }
 
@@ -307,14 +305,24 @@ impl Line {
 
 impl fmt::Display for Line {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use crate::SrecType::*;
+
+        // Two hex digits per address byte
+        let width = match self.rec_type {
+            SrecData24 | SrecCount24 | SrecStart24 => 6,
+            SrecData32 | SrecStart32 => 8,
+            _ => 4,
+        };
+
         write!(
             f,
-            "S{}{:02X}{:04X}{}{:02X}",
+            "S{}{:02X}{:0width$X}{}{:02X}",
             self.rec_type.to_char(),
             self.byte_count(),
             self.address,
             to_hex_string(&self.data),
-            self.gen_checksum()
+            self.gen_checksum(),
+            width = width
         )
     }
 }
@@ -369,6 +377,28 @@ mod tests {
 
     test_checksum!(test_start16, SrecStart16, 0x0000, 0xFC, []);
 
+    #[test]
+    fn test_display_address_width() {
+        let line = |rec_type, address| Line {
+            rec_type,
+            address,
+            data: vec![0x01],
+        };
+
+        assert_eq!(
+            line(SrecType::SrecData16, 0x8000).to_string(),
+            "S1048000017A"
+        );
+        assert_eq!(
+            line(SrecType::SrecData24, 0x18000).to_string(),
+            "S2050180000178"
+        );
+        assert_eq!(
+            line(SrecType::SrecData32, 0x18000).to_string(),
+            "S306000180000177"
+        );
+    }
+
     #[test]
     fn test_convert_hex() {
         let hex_str = "0123456789ABCDEF".as_bytes();